@@ -9,7 +9,13 @@ async fn main() {
     let mut id = String::new();
     io::stdin().read_line(&mut id).unwrap();
     let id = id.trim_end().parse().unwrap();
-    connect(id, handle_packet).await.unwrap();
+    let transport = if std::env::args().any(|arg| arg == "--ws") {
+        Transport::WebSocket
+    } else {
+        Transport::Tcp
+    };
+    let options = ConnectOptions::default().transport(transport);
+    connect_with_options(id, options, handle_packet).await.unwrap();
 
     // let (fut, handle) = futures_util::future::abortable(connect(id, handle_packet));
     // tokio::spawn(fut);
@@ -23,7 +29,7 @@ async fn handle_packet(pk: ChatPacket) {
             println!("成功连接到 Bilibili 弹幕服务器");
         }
         ChatPacket::Popularity(p) => {
-            println!("[人气值] {}", p);
+            println!("[人气值] {}", p.value);
         }
         ChatPacket::Message(msg) => match msg {
             Live => println!("[开播]"),
@@ -77,8 +83,8 @@ async fn handle_packet(pk: ChatPacket) {
             } => {
                 eprintln!("欢迎 {} {} 进入直播间", guard_level, uname);
             }
-            RoomRealTimeMessageUpdate { fans } => {
-                println!("[粉丝数] {}", fans);
+            RoomRealTimeMessageUpdate { fans, fans_club } => {
+                println!("[粉丝数] {} [粉丝团] {}", fans, fans_club);
             }
             RoomRank {
                 rank_desc,
@@ -104,9 +110,14 @@ async fn handle_packet(pk: ChatPacket) {
                 sender_name, message_jpn, price
             ),
             HotRoomNotify => println!("[热门直播间]"),
-            Raw(json) => println!("{}", json.to_string()),
-            ParsingError(str) => panic!("failed to parse json: {}", str),
+            Raw(json) => println!("{}", json),
+            ParsingError { cmd, raw } => panic!(
+                "failed to parse json of {}: {}",
+                cmd.as_deref().unwrap_or("unknown command"),
+                raw
+            ),
             _ => {}
         },
+        _ => {}
     }
 }
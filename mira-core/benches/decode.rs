@@ -0,0 +1,25 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mira_core::msg::Message;
+
+/// A danmaku in the layout with the user in `info[0][15]`
+const DANMU_MSG: &str = include_str!("danmu_msg.json");
+
+fn parse_danmaku(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(DANMU_MSG.len() as u64));
+    // What the codec does for each message in a frame
+    group.bench_function("DANMU_MSG", |b| {
+        b.iter(|| Message::parse(json::parse(DANMU_MSG).unwrap()).unwrap())
+    });
+    group.bench_function("DANMU_MSG from json", |b| {
+        b.iter_batched(
+            || json::parse(DANMU_MSG).unwrap(),
+            |json| Message::parse(json).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse_danmaku);
+criterion_main!(benches);
@@ -1,63 +1,484 @@
-mod http_api {
-    use anyhow::{bail, Error};
-    use hyper::{Client, Body};
+pub mod http_api {
+    use anyhow::{anyhow, bail, Error};
+    use futures_util::stream::{self, StreamExt};
+    use hyper::{Client, Body, Request};
+    use hyper::header::{CONTENT_TYPE, COOKIE};
     use hyper_tls::HttpsConnector;
     use hyper::client::HttpConnector;
     use hyper::client::connect::dns::GaiResolver;
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-    const API_ROOM_INIT: &str = "https://api.live.bilibili.com/room/v1/Room/room_init?id=";
-    const API_ROOM_CONF: &str = "https://api.live.bilibili.com/room/v1/Danmu/getConf?room_id=";
+    const LIVE_ROOM_URL: &str = "https://live.bilibili.com/";
+    pub(crate) const API_LIVE: &str = "https://api.live.bilibili.com";
+    const API_ROOM_INIT: &str = "/room/v1/Room/room_init?id=";
+    const API_ROOM_CONF: &str = "/room/v1/Danmu/getConf?room_id=";
+    const API_SEND_MSG: &str = "/msg/send";
+    const API_ROOM_INFO: &str = "/xlive/web-room/v1/index/getInfoByRoom?room_id=";
+    const API_GIFT_CONFIG: &str = "/xlive/web-room/v1/giftPanel/giftConfig?platform=pc&room_id=";
+    /// Requests in flight at once in `get_room_ids`
+    const ROOM_IDS_CONCURRENCY: usize = 8;
+    const API_FINGER_SPI: &str = "https://api.bilibili.com/x/frontend/finger/spi";
+
+    /// Cookies of a logged-in account
+    pub struct AuthOptions {
+        /// `SESSDATA` cookie
+        pub sessdata: String,
+        /// `bili_jct` cookie, also used as the CSRF token
+        pub bili_jct: String,
+        /// `buvid3` cookie, see `acquire_buvid`
+        pub buvid3: String,
+    }
+
+    /// 直播间信息
+    pub struct RoomInfo {
+        /// 主播 uid
+        pub uid: u32,
+        /// 主播用户名
+        pub uname: String,
+        pub title: String,
+        /// 0: 未开播，1: 直播中，2: 轮播中
+        pub live_status: u32,
+        pub cover_url: String,
+    }
+
+    /// 礼物配置
+    pub struct GiftConfig {
+        pub id: u32,
+        pub name: String,
+        /// 瓜子类型，`gold` 或 `silver`
+        pub coin_type: String,
+        /// 单价（瓜子）
+        pub price: u32,
+        /// 礼物动图
+        pub webp: String,
+    }
+
+    /// Returns the url of the live room. Both short and real room ids work.
+    pub fn room_url(id: u32) -> String {
+        format!("{}{}", LIVE_ROOM_URL, id)
+    }
 
     pub async fn get_room_id(
         client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
         id: u32,
     ) -> Result<u32, Error> {
-        let uri = format!("{}{}", API_ROOM_INIT, id).parse().unwrap();
-        let resp = client.get(uri).await?;
-        let bytes = hyper::body::to_bytes(resp).await?;
-        let str = unsafe { std::str::from_utf8_unchecked(&bytes) };
-        let json = json::parse(str).unwrap();
-        if json["code"] != 0 {
-            bail!("Bilibili API error: {}", json["msg"].as_str().unwrap());
-        }
-        Ok(json["data"]["room_id"].as_u32().unwrap())
+        get_room_id_from(client, API_LIVE, id).await
+    }
+
+    /// Like `get_room_id`, asking the live API at `api` instead.
+    pub(crate) async fn get_room_id_from(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        api: &str,
+        id: u32,
+    ) -> Result<u32, Error> {
+        let uri = format!("{}{}{}", api, API_ROOM_INIT, id);
+        let json = request(client, Request::get(uri).body(Body::empty())?).await?;
+        json["data"]["room_id"]
+            .as_u32()
+            .ok_or_else(|| anyhow!("unexpected room_init response"))
+    }
+
+    /// Resolves many room ids concurrently, mapping each of `ids` to its real
+    /// room id or to the error resolving it.
+    pub async fn get_room_ids(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        ids: &[u32],
+    ) -> HashMap<u32, Result<u32, Error>> {
+        get_room_ids_from(client, API_LIVE, ids).await
+    }
+
+    async fn get_room_ids_from(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        api: &str,
+        ids: &[u32],
+    ) -> HashMap<u32, Result<u32, Error>> {
+        let client = &*client;
+        stream::iter(ids.iter().copied())
+            .map(|id| async move {
+                let mut client = client.clone();
+                (id, get_room_id_from(&mut client, api, id).await)
+            })
+            .buffer_unordered(ROOM_IDS_CONCURRENCY)
+            .collect()
+            .await
     }
 
     pub async fn get_room_token(
         client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
         id: u32,
     ) -> Result<String, Error> {
-        let uri = format!("{}{}", API_ROOM_CONF, id).parse().unwrap();
-        let resp = client.get(uri).await?;
+        get_room_token_from(client, API_LIVE, id).await
+    }
+
+    /// Like `get_room_token`, asking the live API at `api` instead.
+    pub(crate) async fn get_room_token_from(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        api: &str,
+        id: u32,
+    ) -> Result<String, Error> {
+        let uri = format!("{}{}{}", api, API_ROOM_CONF, id);
+        let json = request(client, Request::get(uri).body(Body::empty())?).await?;
+        Ok(json["data"]["token"].to_string())
+    }
+
+    /// Sends a danmaku to the room as the account of `auth`.
+    pub async fn send_danmaku(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        room_id: u32,
+        msg: &str,
+        auth: &AuthOptions,
+    ) -> Result<(), Error> {
+        request(client, danmaku_request(API_LIVE, room_id, msg, auth)?).await?;
+        Ok(())
+    }
+
+    /// Builds the request of `send_danmaku` to the live API at `api`.
+    fn danmaku_request(
+        api: &str,
+        room_id: u32,
+        msg: &str,
+        auth: &AuthOptions,
+    ) -> Result<Request<Body>, Error> {
+        let rnd = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("bubble", "0")
+            .append_pair("msg", msg)
+            .append_pair("color", "16777215")
+            .append_pair("mode", "1")
+            .append_pair("fontsize", "25")
+            .append_pair("rnd", &rnd.to_string())
+            .append_pair("roomid", &room_id.to_string())
+            .append_pair("csrf", &auth.bili_jct)
+            .append_pair("csrf_token", &auth.bili_jct)
+            .finish();
+        let req = Request::post(format!("{}{}", api, API_SEND_MSG))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(
+                COOKIE,
+                format!(
+                    "SESSDATA={}; bili_jct={}; buvid3={}",
+                    auth.sessdata, auth.bili_jct, auth.buvid3
+                ),
+            )
+            .body(Body::from(form))?;
+        Ok(req)
+    }
+
+    /// Fetches a new `buvid3` device id, which Bilibili otherwise sets as a
+    /// cookie when visiting the homepage.
+    pub async fn acquire_buvid(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+    ) -> Result<String, Error> {
+        acquire_buvid_from(client, API_FINGER_SPI).await
+    }
+
+    async fn acquire_buvid_from(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        url: &str,
+    ) -> Result<String, Error> {
+        let mut json = request(client, Request::get(url).body(Body::empty())?).await?;
+        json["data"]["b_3"]
+            .take_string()
+            .ok_or_else(|| anyhow!("unexpected buvid response"))
+    }
+
+    /// Fetches the online count from `url`, which should answer like
+    /// `getOnlineGoldRank` with the count in `data.onlineNum`.
+    pub async fn get_online_count(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        url: &str,
+    ) -> Result<u32, Error> {
+        let json = request(client, Request::get(url).body(Body::empty())?).await?;
+        json["data"]["onlineNum"]
+            .as_u32()
+            .ok_or_else(|| anyhow!("unexpected online count response"))
+    }
+
+    /// Fetches the streamer and live status of the room.
+    pub async fn get_room_info(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        room_id: u32,
+    ) -> Result<RoomInfo, Error> {
+        let uri = format!("{}{}{}", API_LIVE, API_ROOM_INFO, room_id);
+        let mut json = request(client, Request::get(uri).body(Body::empty())?).await?;
+        parse_room_info(&mut json["data"]).ok_or_else(|| anyhow!("unexpected room info response"))
+    }
+
+    fn parse_room_info(data: &mut json::JsonValue) -> Option<RoomInfo> {
+        let uname = data["anchor_info"]["base_info"]["uname"].take_string()?;
+        let room = &mut data["room_info"];
+        Some(RoomInfo {
+            uid: room["uid"].as_u32()?,
+            uname,
+            title: room["title"].take_string()?,
+            live_status: room["live_status"].as_u32()?,
+            cover_url: room["cover"].take_string()?,
+        })
+    }
+
+    /// Fetches the gifts available in the room.
+    pub async fn get_gift_config(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        room_id: u32,
+    ) -> Result<Vec<GiftConfig>, Error> {
+        let uri = format!("{}{}{}", API_LIVE, API_GIFT_CONFIG, room_id);
+        let mut json = request(client, Request::get(uri).body(Body::empty())?).await?;
+        parse_gift_config(&mut json["data"])
+            .ok_or_else(|| anyhow!("unexpected gift config response"))
+    }
+
+    fn parse_gift_config(data: &mut json::JsonValue) -> Option<Vec<GiftConfig>> {
+        data["list"]
+            .members_mut()
+            .map(|gift| {
+                Some(GiftConfig {
+                    id: gift["id"].as_u32()?,
+                    name: gift["name"].take_string()?,
+                    coin_type: gift["coin_type"].take_string()?,
+                    price: gift["price"].as_u32()?,
+                    webp: gift["webp"].take_string()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Sends the request and returns the response json if the API succeeded.
+    async fn request(
+        client: &mut Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+        req: Request<Body>,
+    ) -> Result<json::JsonValue, Error> {
+        let resp = client.request(req).await?;
         let bytes = hyper::body::to_bytes(resp).await?;
-        let str = unsafe { std::str::from_utf8_unchecked(&bytes) };
-        let json = json::parse(str).unwrap();
+        let str = std::str::from_utf8(&bytes)?;
+        let json = json::parse(str)
+            .map_err(|e| anyhow!("invalid Bilibili API response: {}: {}", e, str))?;
         if json["code"] != 0 {
-            bail!("Bilibili API error: {}", json["msg"].as_str().unwrap());
+            // Some APIs put the error text in `message` instead
+            let msg = ["msg", "message"]
+                .iter()
+                .filter_map(|key| json[*key].as_str())
+                .find(|msg| !msg.is_empty())
+                .unwrap_or(str);
+            bail!("Bilibili API error: {}", msg);
+        }
+        Ok(json)
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Response, Server};
+        use std::convert::Infallible;
+        use std::future;
+        use std::sync::Arc;
+
+        pub(crate) fn client() -> Client<HttpsConnector<HttpConnector<GaiResolver>>, Body> {
+            Client::builder().build(HttpsConnector::new())
+        }
+
+        /// Answers every request with `respond` on a local port, returning the
+        /// url of the server.
+        pub(crate) fn serve<F>(respond: F) -> String
+            where
+                F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+        {
+            let respond = Arc::new(respond);
+            let make_service = make_service_fn(move |_| {
+                let respond = respond.clone();
+                future::ready(Ok::<_, Infallible>(service_fn(move |req| {
+                    future::ready(Ok::<_, Infallible>(respond(req)))
+                })))
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+            let url = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+            url
+        }
+
+        #[test]
+        fn formats_room_urls() {
+            assert_eq!(room_url(21919321), "https://live.bilibili.com/21919321");
+        }
+
+        #[tokio::test]
+        async fn builds_danmaku_requests() {
+            let auth = AuthOptions {
+                sessdata: "abc%2C123".to_owned(),
+                bili_jct: "0123abcd".to_owned(),
+                buvid3: "AAAA-BBBB-infoc".to_owned(),
+            };
+            let req = danmaku_request("http://localhost", 22637261, "你好 & 晚上好", &auth).unwrap();
+            assert_eq!(req.method(), "POST");
+            assert_eq!(req.uri(), "http://localhost/msg/send");
+            assert_eq!(req.headers()[CONTENT_TYPE], "application/x-www-form-urlencoded");
+            assert_eq!(
+                req.headers()[COOKIE],
+                "SESSDATA=abc%2C123; bili_jct=0123abcd; buvid3=AAAA-BBBB-infoc",
+            );
+
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let form: HashMap<_, _> = form_urlencoded::parse(&body).into_owned().collect();
+            assert_eq!(form["msg"], "你好 & 晚上好");
+            assert_eq!(form["roomid"], "22637261");
+            assert_eq!(form["csrf"], "0123abcd");
+            assert_eq!(form["csrf_token"], "0123abcd");
+        }
+
+        const ROOM_INFO: &str = r#"{
+            "code": 0,
+            "message": "0",
+            "data": {
+                "room_info": {
+                    "uid": 50329118,
+                    "room_id": 21919321,
+                    "short_id": 0,
+                    "title": "晚间杂谈",
+                    "cover": "https://i0.hdslb.com/bfs/live/new_room_cover/cover.jpg",
+                    "live_status": 1,
+                    "online": 20134
+                },
+                "anchor_info": {
+                    "base_info": {
+                        "uname": "某主播",
+                        "face": "https://i0.hdslb.com/bfs/face/face.jpg"
+                    }
+                }
+            }
+        }"#;
+
+        #[test]
+        fn parses_room_info() {
+            let mut json = json::parse(ROOM_INFO).unwrap();
+            let info = parse_room_info(&mut json["data"]).unwrap();
+            assert_eq!(info.uid, 50329118);
+            assert_eq!(info.uname, "某主播");
+            assert_eq!(info.title, "晚间杂谈");
+            assert_eq!(info.live_status, 1);
+            assert_eq!(info.cover_url, "https://i0.hdslb.com/bfs/live/new_room_cover/cover.jpg");
+
+            let mut json = json::parse(ROOM_INFO).unwrap();
+            json["data"].remove("anchor_info");
+            assert!(parse_room_info(&mut json["data"]).is_none());
+        }
+
+        const GIFT_CONFIG: &str = r#"{
+            "code": 0,
+            "message": "0",
+            "data": {
+                "list": [
+                    {
+                        "id": 1,
+                        "name": "辣条",
+                        "price": 100,
+                        "coin_type": "silver",
+                        "webp": "https://i0.hdslb.com/bfs/live/1.webp"
+                    },
+                    {
+                        "id": 31036,
+                        "name": "小花花",
+                        "price": 100,
+                        "coin_type": "gold",
+                        "webp": "https://i0.hdslb.com/bfs/live/31036.webp"
+                    }
+                ]
+            }
+        }"#;
+
+        #[test]
+        fn parses_gift_config() {
+            let mut json = json::parse(GIFT_CONFIG).unwrap();
+            let gifts = parse_gift_config(&mut json["data"]).unwrap();
+            assert_eq!(gifts.len(), 2);
+            assert_eq!(gifts[0].id, 1);
+            assert_eq!(gifts[0].name, "辣条");
+            assert_eq!(gifts[0].coin_type, "silver");
+            assert_eq!(gifts[1].id, 31036);
+            assert_eq!(gifts[1].price, 100);
+            assert_eq!(gifts[1].webp, "https://i0.hdslb.com/bfs/live/31036.webp");
+
+            let mut json = json::parse(GIFT_CONFIG).unwrap();
+            json["data"]["list"][1].remove("price");
+            assert!(parse_gift_config(&mut json["data"]).is_none());
+        }
+
+        #[tokio::test]
+        async fn acquires_buvid() {
+            let url = serve(|req| {
+                let body = match req.uri().path() {
+                    "/spi" => r#"{"code":0,"data":{"b_3":"6C1D9C5A-infoc","b_4":"F2E1"}}"#,
+                    _ => r#"{"code":-412,"message":"请求被拦截","data":null}"#,
+                };
+                Response::new(Body::from(body))
+            });
+            let buvid = acquire_buvid_from(&mut client(), &format!("{}/spi", url)).await;
+            assert_eq!(buvid.unwrap(), "6C1D9C5A-infoc");
+
+            let e = acquire_buvid_from(&mut client(), &format!("{}/blocked", url)).await;
+            assert_eq!(e.unwrap_err().to_string(), "Bilibili API error: 请求被拦截");
+        }
+
+        #[tokio::test]
+        async fn resolves_room_ids_separately() {
+            let api = serve(|req| {
+                assert_eq!(req.uri().path(), "/room/v1/Room/room_init");
+                let body = match req.uri().query().unwrap() {
+                    "id=1" => r#"{"code":0,"data":{"room_id":1001}}"#,
+                    "id=2" => r#"{"code":0,"data":{"room_id":1002}}"#,
+                    _ => r#"{"code":0,"data":{}}"#,
+                };
+                Response::new(Body::from(body))
+            });
+            let ids = get_room_ids_from(&mut client(), &api, &[1, 2, 3]).await;
+            assert_eq!(ids.len(), 3);
+            assert_eq!(ids[&1].as_ref().unwrap(), &1001);
+            assert_eq!(ids[&2].as_ref().unwrap(), &1002);
+            assert!(ids[&3].is_err());
         }
-        Ok(json["data"]["token"].to_string())
     }
 }
 
 pub mod chat {
+    use super::http_api;
     use super::msg::Message;
-    use anyhow::{bail, Error};
+    use anyhow::{anyhow, bail, Error};
     use bytes::{Buf, BufMut, BytesMut};
     use futures_sink::Sink;
-    use futures_util::{sink::SinkExt, stream::StreamExt};
+    use futures_util::future::{self, AbortHandle, Abortable, Either, FutureExt};
+    use futures_util::pin_mut;
+    use futures_util::{sink::SinkExt, stream::{FuturesUnordered, StreamExt}};
+    use hyper::client::connect::dns::GaiResolver;
+    use hyper::client::HttpConnector;
     use hyper::Client;
     use hyper_tls::HttpsConnector;
-    use miniz_oxide::inflate::decompress_to_vec_zlib as decompress;
+    use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
+    use rand::distributions::{Distribution, WeightedIndex};
+    use rand::Rng;
+    use std::collections::{HashMap, VecDeque};
+    use std::fmt;
     use std::future::Future;
+    use std::io::{Cursor, Write};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
     use tokio::io;
     use tokio::net::TcpStream;
     use tokio::stream::Stream;
-    use tokio::time::{self, Duration};
+    use tokio::sync::{mpsc, oneshot, watch};
+    use tokio::task::JoinHandle;
+    use tokio::time::{self, Duration, Instant};
+    use tokio_tls::TlsStream;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+    use tokio_tungstenite::{stream::Stream as MaybeTlsStream, WebSocketStream};
     use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
 
     const ADDR: (&str, u16) = ("broadcastlv.chat.bilibili.com", 2243);
 
+    const WS_URL: &str = "wss://broadcastlv.chat.bilibili.com/sub";
+
     const HEARTBEAT_DELAY: Duration = Duration::from_secs(30);
 
     const HEADER_LENGTH: usize = 16;
@@ -70,178 +491,2201 @@ pub mod chat {
 
     const SEQUENCE_ID_DEFAULT: u32 = 1;
 
-    pub async fn connect<F, Fut>(id: u32, handle_packet: F) -> Result<(), Error>
-        where
-            F: FnMut(ChatPacket) -> Fut,
-            Fut: Future<Output=()>,
-    {
-        let https_connector = HttpsConnector::new();
-        let mut request_client = Client::builder().build::<_, hyper::Body>(https_connector);
+    const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
 
-        let id = super::http_api::get_room_id(&mut request_client, id).await?;
-        let token = super::http_api::get_room_token(&mut request_client, id).await?;
-        let mut stream = TcpStream::connect(ADDR).await?;
-        let (r, w) = TcpStream::split(&mut stream);
-        let r = FramedRead::new(r, ChatCodec);
-        let w = FramedWrite::new(w, ChatCodec);
+    const CHANNEL_CAPACITY: usize = 256;
 
-        tokio::try_join!(handle_stream(r, handle_packet), handle_sink(w, id, token))?;
+    /// Compression of the messages asked from the server
+    #[cfg(feature = "brotli")]
+    const COMPRESSION: ProtocolVersion = ProtocolVersion::Brotli;
+    #[cfg(not(feature = "brotli"))]
+    const COMPRESSION: ProtocolVersion = ProtocolVersion::Zlib;
 
-        Ok(())
+    /// Options for a chat connection.
+    #[derive(Clone)]
+    pub struct ConnectOptions {
+        idle_timeout: Option<Duration>,
+        handshake_timeout: Option<Duration>,
+        event_log: Option<EventLog>,
+        handler_concurrency: usize,
+        commands: HashMap<String, CommandHandler>,
+        hosts: Vec<(String, u16, u32)>,
+        send_heartbeats: bool,
+        max_decompressed_size: usize,
+        state: Option<Arc<watch::Sender<ConnectionState>>>,
+        online_count: Option<(String, Duration)>,
+        skip_bad_frames: bool,
+        channel_capacity: usize,
+        transport: Transport,
+        ws_url: String,
+        api_url: String,
+        json_recorder: Option<JsonRecorder>,
     }
 
-    async fn handle_stream<F, Fut>(
-        mut stream: impl Stream<Item=Result<Vec<ChatPacket>, Error>> + Unpin,
-        mut handle_packet: F,
-    ) -> Result<(), Error>
-        where
-            F: FnMut(ChatPacket) -> Fut,
-            Fut: Future<Output=()>,
-    {
-        loop {
-            match stream.next().await {
-                Some(res) => {
-                    for pk in res? {
-                        handle_packet(pk).await;
-                    }
-                }
-                None => break,
+    /// Handler of a custom command, receiving its json
+    type CommandHandler = Arc<Mutex<dyn FnMut(json::JsonValue) + Send>>;
+
+    /// Writer receiving the json of every message, see `ConnectOptions::record_json`
+    type JsonRecorder = Arc<Mutex<dyn Write + Send>>;
+
+    impl Default for ConnectOptions {
+        fn default() -> Self {
+            Self {
+                idle_timeout: None,
+                handshake_timeout: None,
+                event_log: None,
+                handler_concurrency: 1,
+                commands: HashMap::new(),
+                hosts: Vec::new(),
+                send_heartbeats: true,
+                max_decompressed_size: MAX_DECOMPRESSED_SIZE,
+                state: None,
+                online_count: None,
+                skip_bad_frames: false,
+                channel_capacity: CHANNEL_CAPACITY,
+                transport: Transport::Tcp,
+                ws_url: WS_URL.to_owned(),
+                api_url: http_api::API_LIVE.to_owned(),
+                json_recorder: None,
             }
         }
-        Ok(())
     }
 
-    async fn handle_sink(
-        mut sink: impl Sink<RawChatPacket, Error=io::Error> + Unpin,
-        id: u32,
-        token: String,
-    ) -> Result<(), Error> {
-        sink.send(RawChatPacket::authenticate(id, token)).await?;
-        loop {
-            sink.send(RawChatPacket::heartbeat()).await?;
-            time::delay_for(HEARTBEAT_DELAY).await;
+    impl ConnectOptions {
+        /// Fails the connection if no packet arrives within `timeout`.
+        ///
+        /// This catches sockets that stay open but go silent. Since the server
+        /// only replies to heartbeats every 30 seconds on a quiet room, the
+        /// timeout should be well above that. Fails with `TimeoutError::Idle`.
+        pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+            self.idle_timeout = Some(timeout);
+            self
+        }
+
+        /// Fails the connection if `ConnectSuccess` isn't received within `timeout`
+        /// after authenticating.
+        ///
+        /// Without it, a connection whose authentication is silently rejected hangs.
+        /// Fails with `TimeoutError::Handshake`.
+        pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+            self.handshake_timeout = Some(timeout);
+            self
+        }
+
+        /// Runs up to `n` packet handlers at once, 1 by default.
+        ///
+        /// The socket keeps being read while handlers run, buffering up to about
+        /// `n` packets ahead, so a slow handler doesn't hold back the heartbeat
+        /// replies. With more than one handler
+        /// in flight, packets may finish handling out of order. Has no effect
+        /// on batched connections.
+        pub fn handler_concurrency(mut self, n: usize) -> Self {
+            self.handler_concurrency = n.max(1);
+            self
+        }
+
+        /// Registers `handler` to receive the json of every `cmd` message.
+        ///
+        /// This is meant for commands the crate doesn't parse yet: the handler
+        /// gets them instead of a `Message::Raw` packet. Commands listed in
+        /// `Message::known_commands` are still parsed as usual. Connections
+        /// made with cloned options share the handler.
+        pub fn register_command<F>(mut self, cmd: &str, handler: F) -> Self
+            where
+                F: FnMut(json::JsonValue) + Send + 'static,
+        {
+            self.commands.insert(cmd.to_owned(), Arc::new(Mutex::new(handler)));
+            self
+        }
+
+        /// Connects to one of the `(host, port, weight)` servers instead of the
+        /// default one.
+        ///
+        /// A server is picked at random with a probability proportional to its
+        /// weight. If it refuses the connection, the next pick is made among the
+        /// remaining servers.
+        pub fn hosts(mut self, hosts: Vec<(String, u16, u32)>) -> Self {
+            self.hosts = hosts;
+            self
+        }
+
+        /// Whether to send heartbeats, `true` by default.
+        ///
+        /// Disable it when connecting through a relay that sends heartbeats by
+        /// itself. Without heartbeats the server doesn't report popularity.
+        pub fn send_heartbeats(mut self, send: bool) -> Self {
+            self.send_heartbeats = send;
+            self
+        }
+
+        /// Fails the connection if a compressed frame expands to more than
+        /// `size` bytes, 16 MiB by default.
+        pub fn max_decompressed_size(mut self, size: usize) -> Self {
+            self.max_decompressed_size = size;
+            self
+        }
+
+        /// Polls the online count from `url` every `interval`, delivering it
+        /// as `ChatPacket::OnlineCount`.
+        ///
+        /// The online count isn't sent over the socket, so it has to be fetched
+        /// from an API such as `getOnlineGoldRank`, see
+        /// `http_api::get_online_count`. Failed polls are skipped.
+        pub fn online_count(mut self, url: &str, interval: Duration) -> Self {
+            self.online_count = Some((url.to_owned(), interval));
+            self
+        }
+
+        /// Whether to skip frames failing to decode instead of failing the
+        /// connection, `false` by default.
+        ///
+        /// Only errors where `CodecError::is_skippable` holds are skipped. They
+        /// are still recorded into the event log.
+        pub fn skip_bad_frames(mut self, skip: bool) -> Self {
+            self.skip_bad_frames = skip;
+            self
+        }
+
+        /// Buffers up to `n` packets in the channel of `spawn_connect`, 256 by
+        /// default.
+        ///
+        /// Reading from the socket pauses while the channel is full.
+        pub fn channel_capacity(mut self, n: usize) -> Self {
+            self.channel_capacity = n.max(1);
+            self
+        }
+
+        /// How to reach the chat server, `Transport::Tcp` by default.
+        pub fn transport(mut self, transport: Transport) -> Self {
+            self.transport = transport;
+            self
+        }
+
+        /// Connects `Transport::WebSocket` to `url` instead of the default
+        /// server. A `ws://` url connects without TLS.
+        pub fn ws_url(mut self, url: &str) -> Self {
+            self.ws_url = url.to_owned();
+            self
+        }
+
+        /// Resolves the room id and token through the live API at `url`
+        /// instead of `https://api.live.bilibili.com`.
+        pub fn api_url(mut self, url: &str) -> Self {
+            self.api_url = url.to_owned();
+            self
+        }
+
+        /// Writes the json of every message received to `writer`, one per line,
+        /// to capture a session as JSONL for replay or analysis.
+        ///
+        /// Messages are still parsed and delivered as usual. Connections made
+        /// with cloned options share the writer, and failed writes are ignored.
+        /// Wrap a file in a `BufWriter`, it's flushed once the last connection
+        /// using it ends.
+        pub fn record_json<W>(mut self, writer: W) -> Self
+            where
+                W: Write + Send + 'static,
+        {
+            self.json_recorder = Some(Arc::new(Mutex::new(writer)));
+            self
+        }
+
+        /// Records the lifecycle events of the connection into `log`.
+        pub fn event_log(mut self, log: EventLog) -> Self {
+            self.event_log = Some(log);
+            self
         }
     }
 
-    pub enum ChatPacket {
-        ConnectSuccess,
-        Popularity(u32),
-        Message(Message),
+    /// How to reach the chat server
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Transport {
+        /// A raw TCP socket on port 2243
+        Tcp,
+        /// A WebSocket over TLS, which some messages are only sent to, see
+        /// `ConnectOptions::ws_url`
+        ///
+        /// `ConnectOptions::hosts` doesn't apply to it.
+        WebSocket,
     }
 
-    struct RawChatPacket {
-        proto_ver: u16,
-        operation: u32,
-        payload: Vec<u8>,
+    /// A bounded log of the latest connection events, for debugging.
+    ///
+    /// Clones share the same buffer, so keep one to inspect the log while
+    /// the connection runs.
+    #[derive(Clone)]
+    pub struct EventLog {
+        events: Arc<Mutex<VecDeque<Event>>>,
+        capacity: usize,
     }
 
-    impl RawChatPacket {
-        fn authenticate(room_id: u32, token: String) -> Self {
+    impl EventLog {
+        /// Creates a log keeping the last `capacity` events.
+        pub fn new(capacity: usize) -> Self {
             Self {
-                proto_ver: 1,
-                operation: OP_USER_AUTHENTICATION,
-                payload: format!(r#"{{"roomid":{},"protover":2,"token":"{}","uid":0}}"#, room_id, token).into_bytes(),
+                events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+                capacity,
             }
         }
 
-        const HEARTBEAT: Self = Self {
-            proto_ver: 1,
-            operation: OP_HEARTBEAT,
-            payload: Vec::new(),
-        };
+        /// Returns the recorded events, oldest first.
+        pub fn snapshot(&self) -> Vec<Event> {
+            self.events.lock().unwrap().iter().cloned().collect()
+        }
 
-        fn heartbeat() -> Self {
-            Self::HEARTBEAT
+        fn push(&self, kind: EventKind) {
+            if self.capacity == 0 {
+                return;
+            }
+            let mut events = self.events.lock().unwrap();
+            if events.len() == self.capacity {
+                events.pop_front();
+            }
+            events.push_back(Event {
+                time: SystemTime::now(),
+                kind,
+            });
         }
     }
 
-    /// Codec for chat packets
-    ///
-    /// packet length: u32
-    /// header length: u16 (16)
-    /// protocol version: u16
-    /// operation: u32
-    /// sequence: u32 (1)
-    /// data: [u8]
-    struct ChatCodec;
+    /// An event recorded by `EventLog`
+    #[derive(Clone, Debug)]
+    pub struct Event {
+        pub time: SystemTime,
+        pub kind: EventKind,
+    }
 
-    impl Encoder<RawChatPacket> for ChatCodec {
-        type Error = io::Error;
+    #[derive(Clone, Debug)]
+    pub enum EventKind {
+        /// The socket is connected
+        Connect,
+        /// The authentication packet is sent
+        Authenticate,
+        /// A heartbeat is sent
+        Heartbeat,
+        /// The server closed the connection
+        Disconnect,
+        /// A frame failed to decode
+        DecodeError(String),
+    }
 
-        fn encode(&mut self, pk: RawChatPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
-            let len = HEADER_LENGTH + pk.payload.len();
-            dst.reserve(len);
-            dst.put_u32(len as u32);
-            dst.put_u16(HEADER_LENGTH as u16);
-            dst.put_u16(pk.proto_ver);
-            dst.put_u32(pk.operation);
-            dst.put_u32(SEQUENCE_ID_DEFAULT);
-            dst.put(&pk.payload[..]);
-            Ok(())
+    fn record(log: Option<&EventLog>, kind: EventKind) {
+        if let Some(log) = log {
+            log.push(kind);
         }
     }
 
-    impl Decoder for ChatCodec {
-        type Item = Vec<ChatPacket>;
-        type Error = Error;
+    /// State of a connection, see `connect_with_handle`
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum ConnectionState {
+        /// Resolving the room and opening the socket
+        Connecting,
+        /// Waiting for `ConnectSuccess`
+        Authenticating,
+        /// Receiving packets
+        Connected,
+        /// The connection has ended, normally or with an error
+        Disconnected,
+    }
 
-        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-            let src_len = src.len();
-            if src_len < 4 {
-                return Ok(None);
-            }
-            let mut cur = src.as_ref();
-            let len = cur.get_u32() as usize;
-            if src_len < len {
-                // Reserved bytes counts from the current index
-                src.reserve(len);
-                return Ok(None);
-            }
-            cur.advance(2); // header length
-            let proto_ver = cur.get_u16();
-            let operation = cur.get_u32();
-            cur.advance(4); // Sequence ID
+    fn set_state(state: Option<&Arc<watch::Sender<ConnectionState>>>, new: ConnectionState) {
+        if let Some(state) = state {
+            let _ = state.broadcast(new);
+        }
+    }
 
-            let mut res = Vec::new();
-            match operation {
-                OP_CONNECT_SUCCESS => res.push(ChatPacket::ConnectSuccess),
-                OP_HEARTBEAT_REPLY => res.push(ChatPacket::Popularity(cur.get_u32())),
-                OP_MESSAGE => {
-                    let decompressed: Vec<u8>;
-                    let mut data = match proto_ver {
-                        0 => &src[0..len],
-                        2 => match decompress(&src[HEADER_LENGTH..len]) {
-                            Ok(res) => {
-                                decompressed = res;
-                                &decompressed[..]
-                            }
-                            Err(_) => bail!("failed to decompress"),
-                        },
-                        _ => bail!("unsupported protocol version: {}", proto_ver),
-                    };
-                    while data.has_remaining() {
-                        let len = data.get_u32() as usize - 4;
-                        let str =
-                            unsafe { std::str::from_utf8_unchecked(&data[HEADER_LENGTH - 4..len]) };
-                        let json = json::parse(str).unwrap();
-                        let msg = Message::parse(json)
-                            .unwrap_or_else(|| Message::ParsingError(str.to_owned()));
-                        res.push(ChatPacket::Message(msg));
-                        data.advance(len);
-                    }
+    pub async fn connect<F, Fut>(id: u32, handle_packet: F) -> Result<(), Error>
+        where
+            F: FnMut(ChatPacket) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        connect_with_options(id, ConnectOptions::default(), handle_packet).await
+    }
+
+    pub async fn connect_with_options<F, Fut>(
+        id: u32,
+        options: ConnectOptions,
+        handle_packet: F,
+    ) -> Result<(), Error>
+        where
+            F: FnMut(ChatPacket) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        let (mut conn, id, token, online_count) = open(id, &options).await?;
+        let (r, w) = conn.split(&options);
+        let r = PacketReader::new(r, &options, online_count);
+
+        let (sink_failed_tx, sink_failed) = oneshot::channel();
+        drive(
+            handle_stream(r, options.handler_concurrency, handle_packet, sink_failed),
+            handle_sink(w, id, token, options.send_heartbeats, options.event_log.clone()),
+            sink_failed_tx,
+        )
+        .await
+    }
+
+    /// Like `connect_with_options`, but also returns a receiver following the
+    /// state of the connection.
+    ///
+    /// The connection only starts once the returned future is polled.
+    pub fn connect_with_handle<F, Fut>(
+        id: u32,
+        mut options: ConnectOptions,
+        handle_packet: F,
+    ) -> (watch::Receiver<ConnectionState>, impl Future<Output=Result<(), Error>>)
+        where
+            F: FnMut(ChatPacket) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        let (tx, rx) = watch::channel(ConnectionState::Connecting);
+        let tx = Arc::new(tx);
+        options.state = Some(tx.clone());
+        let conn = async move {
+            let res = connect_with_options(id, options, handle_packet).await;
+            set_state(Some(&tx), ConnectionState::Disconnected);
+            res
+        };
+        (rx, conn)
+    }
+
+    /// Runs the connection in a spawned task, delivering packets into the
+    /// returned channel.
+    ///
+    /// The handle resolves to the result of the connection once it ends. The
+    /// connection is closed with `Ok(())` once the receiver is dropped.
+    pub fn spawn_connect(
+        id: u32,
+        options: ConnectOptions,
+    ) -> (JoinHandle<Result<(), Error>>, mpsc::Receiver<ChatPacket>) {
+        let (tx, rx) = mpsc::channel(options.channel_capacity);
+        let (stop, stop_registration) = AbortHandle::new_pair();
+        let conn = connect_with_options(id, options, move |pk| {
+            let mut tx = tx.clone();
+            let stop = stop.clone();
+            async move {
+                // Nobody listens anymore
+                if tx.send(pk).await.is_err() {
+                    stop.abort();
                 }
-                _ => (),
             }
-            src.advance(len);
-            Ok(if res.is_empty() { None } else { Some(res) })
-        }
+        });
+        let conn = Abortable::new(conn, stop_registration);
+        let handle = tokio::spawn(async move { conn.await.unwrap_or(Ok(())) });
+        (handle, rx)
     }
-}
-
-pub mod msg {
-    use self::Message::*;
-    use std::fmt;
 
+    /// Like `connect`, but delivers all packets decoded from a frame at once.
+    pub async fn connect_batched<F, Fut>(id: u32, handle_packets: F) -> Result<(), Error>
+        where
+            F: FnMut(Vec<ChatPacket>) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        connect_batched_with_options(id, ConnectOptions::default(), handle_packets).await
+    }
+
+    pub async fn connect_batched_with_options<F, Fut>(
+        id: u32,
+        options: ConnectOptions,
+        handle_packets: F,
+    ) -> Result<(), Error>
+        where
+            F: FnMut(Vec<ChatPacket>) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        let (mut conn, id, token, online_count) = open(id, &options).await?;
+        let (r, w) = conn.split(&options);
+        let r = PacketReader::new(r, &options, online_count);
+
+        let (sink_failed_tx, sink_failed) = oneshot::channel();
+        drive(
+            handle_stream_batched(r, handle_packets, sink_failed),
+            handle_sink(w, id, token, options.send_heartbeats, options.event_log.clone()),
+            sink_failed_tx,
+        )
+        .await
+    }
+
+    /// Connects to rooms with the same options, configured once.
+    ///
+    /// Each connection gets a copy of the options, and registered command
+    /// handlers are shared by all of them. The free functions are shorthands
+    /// for one-off connections.
+    #[derive(Clone, Default)]
+    pub struct ChatClient {
+        options: ConnectOptions,
+    }
+
+    impl ChatClient {
+        pub fn new(options: ConnectOptions) -> Self {
+            Self { options }
+        }
+
+        /// See `connect_with_options`.
+        pub async fn connect<F, Fut>(&self, id: u32, handle_packet: F) -> Result<(), Error>
+            where
+                F: FnMut(ChatPacket) -> Fut,
+                Fut: Future<Output=()>,
+        {
+            connect_with_options(id, self.options.clone(), handle_packet).await
+        }
+
+        /// See `connect_batched_with_options`.
+        pub async fn connect_batched<F, Fut>(&self, id: u32, handle_packets: F) -> Result<(), Error>
+            where
+                F: FnMut(Vec<ChatPacket>) -> Fut,
+                Fut: Future<Output=()>,
+        {
+            connect_batched_with_options(id, self.options.clone(), handle_packets).await
+        }
+
+        /// See `connect_with_handle`.
+        pub fn connect_with_handle<F, Fut>(
+            &self,
+            id: u32,
+            handle_packet: F,
+        ) -> (watch::Receiver<ConnectionState>, impl Future<Output=Result<(), Error>>)
+            where
+                F: FnMut(ChatPacket) -> Fut,
+                Fut: Future<Output=()>,
+        {
+            connect_with_handle(id, self.options.clone(), handle_packet)
+        }
+
+        /// See `spawn_connect`.
+        pub fn spawn_connect(
+            &self,
+            id: u32,
+        ) -> (JoinHandle<Result<(), Error>>, mpsc::Receiver<ChatPacket>) {
+            spawn_connect(id, self.options.clone())
+        }
+    }
+
+    /// Resolves the real room id and token, then opens the socket.
+    ///
+    /// Also starts polling the online count if enabled.
+    async fn open(
+        id: u32,
+        options: &ConnectOptions,
+    ) -> Result<(Connection, u32, String, Option<OnlineCountPoll>), Error> {
+        let https_connector = HttpsConnector::new();
+        let mut request_client = Client::builder().build::<_, hyper::Body>(https_connector);
+
+        let api = &options.api_url;
+        let id = http_api::get_room_id_from(&mut request_client, api, id).await?;
+        let token = http_api::get_room_token_from(&mut request_client, api, id).await?;
+        let conn = match options.transport {
+            Transport::Tcp if options.hosts.is_empty() => {
+                Connection::Tcp(TcpStream::connect(ADDR).await?)
+            }
+            Transport::Tcp => Connection::Tcp(connect_weighted(options.hosts.clone()).await?),
+            Transport::WebSocket => {
+                Connection::WebSocket(Box::new(connect_ws(&options.ws_url).await?))
+            }
+        };
+        record(options.event_log.as_ref(), EventKind::Connect);
+        set_state(options.state.as_ref(), ConnectionState::Authenticating);
+        let online_count = options
+            .online_count
+            .as_ref()
+            .map(|(url, interval)| OnlineCountPoll::spawn(request_client, url.clone(), *interval));
+        Ok((conn, id, token, online_count))
+    }
+
+    type WsStream = WebSocketStream<MaybeTlsStream<TcpStream, TlsStream<TcpStream>>>;
+
+    /// Opens the WebSocket at `url`, over TLS for `wss://` urls.
+    async fn connect_ws(url: &str) -> Result<WsStream, Error> {
+        let uri = url.parse::<hyper::Uri>()?;
+        let host = uri.host().ok_or_else(|| anyhow!("no host in WebSocket url: {}", url))?;
+        let tls = match uri.scheme_str() {
+            Some("wss") => true,
+            Some("ws") => false,
+            _ => bail!("not a WebSocket url: {}", url),
+        };
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        let stream = TcpStream::connect((host, port)).await?;
+        let stream = if tls {
+            let tls = tokio_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+            MaybeTlsStream::Tls(tls.connect(host, stream).await?)
+        } else {
+            MaybeTlsStream::Plain(stream)
+        };
+        let (ws, _) = tokio_tungstenite::client_async(url, stream).await?;
+        Ok(ws)
+    }
+
+    type PacketStream<'a> =
+        Pin<Box<dyn Stream<Item=Result<Vec<ChatPacket>, CodecError>> + Send + 'a>>;
+    type PacketSink<'a> = Pin<Box<dyn Sink<RawChatPacket, Error=io::Error> + Send + 'a>>;
+
+    /// An open connection to the chat server, over either transport.
+    enum Connection {
+        Tcp(TcpStream),
+        WebSocket(Box<WsStream>),
+    }
+
+    impl Connection {
+        /// Splits the connection into a stream of packets and a sink of packets.
+        fn split(&mut self, options: &ConnectOptions) -> (PacketStream<'_>, PacketSink<'_>) {
+            let codec = ChatCodec::new(options);
+            match self {
+                Connection::Tcp(stream) => {
+                    let (r, w) = stream.split();
+                    (
+                        Box::pin(FramedRead::new(r, codec)),
+                        Box::pin(FramedWrite::new(w, ChatCodec::default())),
+                    )
+                }
+                Connection::WebSocket(ws) => {
+                    let (w, r) = ws.split();
+                    // Packets are framed the same way inside binary messages
+                    let w = w
+                        .sink_map_err(io::Error::other)
+                        .with(|pk| {
+                            let mut buf = BytesMut::new();
+                            let res = ChatCodec::default()
+                                .encode(pk, &mut buf)
+                                .map(|()| WsMessage::Binary(buf.to_vec()));
+                            future::ready(res)
+                        });
+                    (Box::pin(ws_packets(r, codec)), Box::pin(w))
+                }
+            }
+        }
+    }
+
+    /// Decodes the packets in the binary messages of `ws`.
+    fn ws_packets<'a>(
+        ws: impl Stream<Item=Result<WsMessage, WsError>> + Unpin + Send + 'a,
+        codec: ChatCodec,
+    ) -> impl Stream<Item=Result<Vec<ChatPacket>, CodecError>> + Send + 'a {
+        let state = Some((ws, codec, BytesMut::new()));
+        futures_util::stream::unfold(state, |state| async move {
+            let (mut ws, mut codec, mut buf) = state?;
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(pks)) => return Some((Ok(pks), Some((ws, codec, buf)))),
+                    Err(e) => return Some((Err(e), Some((ws, codec, buf)))),
+                    Ok(None) => {}
+                }
+                match ws.next().await {
+                    Some(Ok(WsMessage::Binary(data))) => buf.extend_from_slice(&data),
+                    // Pings are answered by the WebSocket itself
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        let e = CodecError::Io(io::Error::other(e));
+                        return Some((Err(e), None));
+                    }
+                    None => {
+                        return match codec.decode_eof(&mut buf) {
+                            Ok(Some(pks)) => Some((Ok(pks), Some((ws, codec, buf)))),
+                            Ok(None) => None,
+                            Err(e) => Some((Err(e), None)),
+                        };
+                    }
+                }
+            }
+        })
+    }
+
+    /// A background task polling the online count, aborted when dropped.
+    struct OnlineCountPoll {
+        counts: mpsc::Receiver<u32>,
+        abort: AbortHandle,
+    }
+
+    impl OnlineCountPoll {
+        fn spawn(
+            mut client: Client<HttpsConnector<HttpConnector<GaiResolver>>, hyper::Body>,
+            url: String,
+            interval: Duration,
+        ) -> Self {
+            let (mut tx, counts) = mpsc::channel(1);
+            let (task, abort) = future::abortable(async move {
+                let mut interval = time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    if let Ok(count) = super::http_api::get_online_count(&mut client, &url).await {
+                        if tx.send(count).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+            tokio::spawn(task);
+            Self { counts, abort }
+        }
+    }
+
+    impl Drop for OnlineCountPoll {
+        fn drop(&mut self) {
+            self.abort.abort();
+        }
+    }
+
+    /// Connects to one of `hosts` picked by weight, failing over to the rest.
+    async fn connect_weighted(mut hosts: Vec<(String, u16, u32)>) -> Result<TcpStream, Error> {
+        loop {
+            let i = pick_weighted(&hosts, &mut rand::thread_rng())?;
+            let (host, port, _) = hosts.swap_remove(i);
+            match TcpStream::connect((host.as_str(), port)).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) if hosts.is_empty() => return Err(e.into()),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Returns the index of a host picked with a probability proportional to its weight.
+    fn pick_weighted(hosts: &[(String, u16, u32)], rng: &mut impl Rng) -> Result<usize, Error> {
+        Ok(WeightedIndex::new(hosts.iter().map(|&(_, _, weight)| weight))?.sample(rng))
+    }
+
+    /// Runs both sides of the connection until the server closes it or either
+    /// side fails.
+    ///
+    /// The sink only returns on a write error. The stream is then told to
+    /// deliver the packets it has already received before the error is returned.
+    async fn drive(
+        stream: impl Future<Output=Result<(), Error>>,
+        sink: impl Future<Output=Result<(), Error>>,
+        sink_failed: oneshot::Sender<()>,
+    ) -> Result<(), Error> {
+        pin_mut!(stream, sink);
+        match future::select(stream, sink).await {
+            Either::Left((res, _)) => res,
+            Either::Right((res, stream)) => {
+                let _ = sink_failed.send(());
+                let stream_res = stream.await;
+                res.and(stream_res)
+            }
+        }
+    }
+
+    async fn handle_stream<F, Fut>(
+        mut reader: PacketReader<impl Stream<Item=Result<Vec<ChatPacket>, CodecError>> + Unpin>,
+        concurrency: usize,
+        mut handle_packet: F,
+        mut sink_failed: oneshot::Receiver<()>,
+    ) -> Result<(), Error>
+        where
+            F: FnMut(ChatPacket) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        if concurrency <= 1 {
+            loop {
+                let pks = tokio::select! {
+                    next = reader.next() => match next? {
+                        Some(pks) => pks,
+                        None => return Ok(()),
+                    },
+                    _ = &mut sink_failed => {
+                        for pk in reader.drain() {
+                            handle_packet(pk).await;
+                        }
+                        return Ok(());
+                    }
+                };
+                for pk in pks {
+                    handle_packet(pk).await;
+                }
+            }
+        }
+
+        let mut in_flight = FuturesUnordered::new();
+        let mut pending = VecDeque::new();
+        let mut eof = false;
+        // Packets already read are still handled before a read error is returned
+        let mut error = None;
+        loop {
+            while in_flight.len() < concurrency {
+                match pending.pop_front() {
+                    Some(pk) => in_flight.push(handle_packet(pk)),
+                    None => break,
+                }
+            }
+            if eof && in_flight.is_empty() {
+                return error.map_or(Ok(()), Err);
+            }
+            tokio::select! {
+                // Stop reading while the handlers are behind, so that a slow
+                // handler pushes back on the socket instead of buffering without bound
+                next = reader.next(), if !eof && pending.len() < concurrency => match next {
+                    Ok(Some(pks)) => pending.extend(pks),
+                    Ok(None) => eof = true,
+                    Err(e) => {
+                        error = Some(e);
+                        eof = true;
+                    }
+                },
+                _ = &mut sink_failed, if !eof => {
+                    pending.extend(reader.drain());
+                    eof = true;
+                }
+                _ = in_flight.next(), if !in_flight.is_empty() => {}
+            }
+        }
+    }
+
+    async fn handle_stream_batched<F, Fut>(
+        mut reader: PacketReader<impl Stream<Item=Result<Vec<ChatPacket>, CodecError>> + Unpin>,
+        mut handle_packets: F,
+        mut sink_failed: oneshot::Receiver<()>,
+    ) -> Result<(), Error>
+        where
+            F: FnMut(Vec<ChatPacket>) -> Fut,
+            Fut: Future<Output=()>,
+    {
+        loop {
+            let pks = tokio::select! {
+                next = reader.next() => match next? {
+                    Some(pks) => pks,
+                    None => return Ok(()),
+                },
+                _ = &mut sink_failed => {
+                    let pks = reader.drain();
+                    if !pks.is_empty() {
+                        handle_packets(pks).await;
+                    }
+                    return Ok(());
+                }
+            };
+            handle_packets(pks).await;
+        }
+    }
+
+    /// Reads packets from the socket, enforcing the timeouts of `ConnectOptions`.
+    struct PacketReader<S> {
+        stream: S,
+        idle_timeout: Option<Duration>,
+        /// Cleared once `ConnectSuccess` is received
+        handshake_deadline: Option<Instant>,
+        event_log: Option<EventLog>,
+        state: Option<Arc<watch::Sender<ConnectionState>>>,
+        online_count: Option<OnlineCountPoll>,
+        /// When the socket last delivered packets, for the idle timeout
+        last_received: Instant,
+        skip_bad_frames: bool,
+    }
+
+    impl<S> PacketReader<S>
+        where
+            S: Stream<Item=Result<Vec<ChatPacket>, CodecError>> + Unpin,
+    {
+        fn new(
+            stream: S,
+            options: &ConnectOptions,
+            online_count: Option<OnlineCountPoll>,
+        ) -> Self {
+            Self {
+                stream,
+                idle_timeout: options.idle_timeout,
+                handshake_deadline: options.handshake_timeout.map(|t| Instant::now() + t),
+                event_log: options.event_log.clone(),
+                state: options.state.clone(),
+                online_count,
+                last_received: Instant::now(),
+                skip_bad_frames: options.skip_bad_frames,
+            }
+        }
+
+        async fn next(&mut self) -> Result<Option<Vec<ChatPacket>>, Error> {
+            loop {
+                let idle_deadline = self.idle_timeout.map(|t| self.last_received + t);
+                let deadline = match (self.handshake_deadline, idle_deadline) {
+                    (Some(handshake), Some(idle)) => Some(handshake.min(idle)),
+                    (handshake, idle) => handshake.or(idle),
+                };
+                let stream = &mut self.stream;
+                let read = async {
+                    match deadline {
+                        Some(deadline) => time::timeout_at(deadline, stream.next()).await,
+                        None => Ok(stream.next().await),
+                    }
+                };
+                let read = match &mut self.online_count {
+                    Some(poll) => tokio::select! {
+                        read = read => read,
+                        Some(count) = poll.counts.recv() => {
+                            return Ok(Some(vec![ChatPacket::OnlineCount(count)]));
+                        }
+                    },
+                    None => read.await,
+                };
+                let next = match read {
+                    Ok(next) => next,
+                    Err(_) if self.handshake_deadline == deadline => {
+                        return Err(TimeoutError::Handshake.into());
+                    }
+                    Err(_) => return Err(TimeoutError::Idle(self.idle_timeout.unwrap()).into()),
+                };
+                let pks = match next {
+                    Some(Ok(pks)) => {
+                        self.last_received = Instant::now();
+                        Some(pks)
+                    }
+                    Some(Err(e)) => {
+                        record(self.event_log.as_ref(), EventKind::DecodeError(e.to_string()));
+                        if self.skip_bad_frames && e.is_skippable() {
+                            continue;
+                        }
+                        return Err(e.into());
+                    }
+                    None => {
+                        record(self.event_log.as_ref(), EventKind::Disconnect);
+                        None
+                    }
+                };
+                if let Some(pks) = &pks {
+                    if pks.iter().any(|pk| matches!(pk, ChatPacket::ConnectSuccess)) {
+                        self.handshake_deadline = None;
+                        set_state(self.state.as_ref(), ConnectionState::Connected);
+                    }
+                }
+                return Ok(pks);
+            }
+        }
+
+        /// Returns the packets that can be decoded without waiting for the socket.
+        fn drain(&mut self) -> Vec<ChatPacket> {
+            let mut pks = Vec::new();
+            while let Some(Some(Ok(next))) = self.stream.next().now_or_never() {
+                pks.extend(next);
+            }
+            pks
+        }
+    }
+
+    async fn handle_sink(
+        mut sink: impl Sink<RawChatPacket, Error=io::Error> + Unpin,
+        id: u32,
+        token: String,
+        send_heartbeats: bool,
+        event_log: Option<EventLog>,
+    ) -> Result<(), Error> {
+        sink.send(RawChatPacket::authenticate(id, token)).await?;
+        record(event_log.as_ref(), EventKind::Authenticate);
+        if !send_heartbeats {
+            return future::pending().await;
+        }
+        loop {
+            sink.send(RawChatPacket::heartbeat()).await?;
+            record(event_log.as_ref(), EventKind::Heartbeat);
+            time::delay_for(HEARTBEAT_DELAY).await;
+        }
+    }
+
+    /// A packet received from the chat server.
+    ///
+    /// More kinds of packets may be added, so matching on it needs a wildcard arm.
+    #[non_exhaustive]
+    pub enum ChatPacket {
+        ConnectSuccess,
+        Popularity(Popularity),
+        /// 在线人数，见 `ConnectOptions::online_count`
+        OnlineCount(u32),
+        Message(Message),
+    }
+
+    /// Popularity reported by a heartbeat reply
+    pub struct Popularity {
+        pub value: u32,
+        /// Json following the number in some replies
+        pub extra: Option<json::JsonValue>,
+    }
+
+    /// The server stayed silent for too long, returned by the connect functions
+    /// and recoverable with `Error::downcast_ref`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TimeoutError {
+        /// `ConnectSuccess` didn't arrive within `ConnectOptions::handshake_timeout`
+        Handshake,
+        /// No packet arrived within `ConnectOptions::idle_timeout`
+        Idle(Duration),
+    }
+
+    impl fmt::Display for TimeoutError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TimeoutError::Handshake => f.write_str("authentication timed out"),
+                TimeoutError::Idle(timeout) => write!(f, "no data received in {:?}", timeout),
+            }
+        }
+    }
+
+    impl std::error::Error for TimeoutError {}
+
+    /// An error decoding a frame from the chat server
+    #[derive(Debug)]
+    pub enum CodecError {
+        /// A frame or a packet in it is shorter than its content requires
+        ShortBuffer,
+        /// A compressed frame expands past `ConnectOptions::max_decompressed_size`
+        OversizedFrame(usize),
+        /// A compressed frame failed to decompress
+        Decompress,
+        /// A message isn't valid UTF-8
+        InvalidUtf8,
+        /// The protocol version of a frame is unknown
+        UnsupportedProtocol(u16),
+        /// The packet or header length of a frame is invalid, so the frames
+        /// can't be told apart anymore
+        InvalidHeader(usize),
+        /// The socket was closed in the middle of a frame
+        UnexpectedEof { buffered: usize },
+        Io(io::Error),
+    }
+
+    impl CodecError {
+        /// Returns `true` if only the frame is bad and the following frames
+        /// can still be decoded, see `ConnectOptions::skip_bad_frames`.
+        pub fn is_skippable(&self) -> bool {
+            matches!(
+                self,
+                CodecError::ShortBuffer
+                    | CodecError::OversizedFrame(_)
+                    | CodecError::Decompress
+                    | CodecError::InvalidUtf8
+                    | CodecError::UnsupportedProtocol(_)
+            )
+        }
+    }
+
+    impl fmt::Display for CodecError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                CodecError::ShortBuffer => f.write_str("frame too short for its content"),
+                CodecError::OversizedFrame(limit) => {
+                    write!(f, "decompressed size exceeds {} bytes", limit)
+                }
+                CodecError::Decompress => f.write_str("failed to decompress"),
+                CodecError::InvalidUtf8 => f.write_str("message is not valid UTF-8"),
+                CodecError::UnsupportedProtocol(ver) => {
+                    write!(f, "unsupported protocol version: {}", ver)
+                }
+                CodecError::InvalidHeader(len) => write!(f, "invalid length in header: {}", len),
+                CodecError::UnexpectedEof { buffered } => {
+                    write!(f, "connection closed mid-frame with {} bytes buffered", buffered)
+                }
+                CodecError::Io(e) => e.fmt(f),
+            }
+        }
+    }
+
+    impl std::error::Error for CodecError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                CodecError::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for CodecError {
+        fn from(e: io::Error) -> Self {
+            CodecError::Io(e)
+        }
+    }
+
+    /// Protocol version in the packet header, telling how the body is encoded
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum ProtocolVersion {
+        /// Uncompressed json
+        Plain,
+        /// Heartbeat and authentication, or the popularity in a heartbeat reply
+        Heartbeat,
+        /// Zlib-compressed batch of packets
+        Zlib,
+        /// Brotli-compressed batch of packets
+        Brotli,
+    }
+
+    impl ProtocolVersion {
+        fn from_u16(n: u16) -> Option<Self> {
+            Some(match n {
+                0 => ProtocolVersion::Plain,
+                1 => ProtocolVersion::Heartbeat,
+                2 => ProtocolVersion::Zlib,
+                3 => ProtocolVersion::Brotli,
+                _ => return None,
+            })
+        }
+
+        fn to_u16(self) -> u16 {
+            match self {
+                ProtocolVersion::Plain => 0,
+                ProtocolVersion::Heartbeat => 1,
+                ProtocolVersion::Zlib => 2,
+                ProtocolVersion::Brotli => 3,
+            }
+        }
+    }
+
+    struct RawChatPacket {
+        proto_ver: ProtocolVersion,
+        operation: u32,
+        payload: Vec<u8>,
+    }
+
+    impl RawChatPacket {
+        fn authenticate(room_id: u32, token: String) -> Self {
+            Self {
+                proto_ver: ProtocolVersion::Heartbeat,
+                operation: OP_USER_AUTHENTICATION,
+                payload: format!(
+                    r#"{{"roomid":{},"protover":{},"token":"{}","uid":0}}"#,
+                    room_id,
+                    COMPRESSION.to_u16(),
+                    token
+                )
+                .into_bytes(),
+            }
+        }
+
+        const HEARTBEAT: Self = Self {
+            proto_ver: ProtocolVersion::Heartbeat,
+            operation: OP_HEARTBEAT,
+            payload: Vec::new(),
+        };
+
+        fn heartbeat() -> Self {
+            Self::HEARTBEAT
+        }
+    }
+
+    /// Codec for chat packets
+    ///
+    /// packet length: u32
+    /// header length: u16 (16)
+    /// protocol version: u16
+    /// operation: u32
+    /// sequence: u32 (1)
+    /// data: [u8]
+    struct ChatCodec {
+        commands: HashMap<String, CommandHandler>,
+        max_decompressed_size: usize,
+        json_recorder: Option<JsonRecorder>,
+        /// A malformed packet found after others in a batch, returned by the
+        /// next `decode` once the packets before it are delivered
+        pending_error: Option<CodecError>,
+    }
+
+    impl ChatCodec {
+        /// Creates a codec with the registered commands of `options`.
+        fn new(options: &ConnectOptions) -> Self {
+            Self {
+                commands: options.commands.clone(),
+                max_decompressed_size: options.max_decompressed_size,
+                json_recorder: options.json_recorder.clone(),
+                pending_error: None,
+            }
+        }
+    }
+
+    impl Default for ChatCodec {
+        fn default() -> Self {
+            Self {
+                commands: HashMap::new(),
+                max_decompressed_size: MAX_DECOMPRESSED_SIZE,
+                json_recorder: None,
+                pending_error: None,
+            }
+        }
+    }
+
+    /// Decompresses brotli data, failing if it expands past `limit` bytes.
+    #[cfg(feature = "brotli")]
+    fn decompress_brotli(input: &[u8], limit: usize) -> Result<Vec<u8>, CodecError> {
+        use std::io::Read;
+
+        let mut out = Vec::new();
+        brotli::Decompressor::new(input, 4096)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|_| CodecError::Decompress)?;
+        if out.len() > limit {
+            return Err(CodecError::OversizedFrame(limit));
+        }
+        Ok(out)
+    }
+
+    /// Decompresses zlib data, failing instead of growing the output past `limit` bytes.
+    fn decompress_zlib(input: &[u8], limit: usize) -> Result<Vec<u8>, CodecError> {
+        let flags = inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER
+            | inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        let mut decomp = Box::<DecompressorOxide>::default();
+        let mut out = vec![0; (input.len() * 2).max(64).min(limit)];
+        let mut in_pos = 0;
+        let mut out_pos = 0;
+        loop {
+            // The whole output is passed each time, as matches refer back into it
+            let mut cur = Cursor::new(out.as_mut_slice());
+            cur.set_position(out_pos as u64);
+            let (status, in_consumed, out_consumed) =
+                decompress(&mut decomp, &input[in_pos..], &mut cur, flags);
+            in_pos += in_consumed;
+            out_pos += out_consumed;
+            match status {
+                TINFLStatus::Done => {
+                    out.truncate(out_pos);
+                    return Ok(out);
+                }
+                TINFLStatus::HasMoreOutput if out.len() < limit => {
+                    let len = (out.len() * 2).min(limit);
+                    out.resize(len, 0);
+                }
+                TINFLStatus::HasMoreOutput => return Err(CodecError::OversizedFrame(limit)),
+                _ => return Err(CodecError::Decompress),
+            }
+        }
+    }
+
+    impl Encoder<RawChatPacket> for ChatCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, pk: RawChatPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+            let len = HEADER_LENGTH + pk.payload.len();
+            dst.reserve(len);
+            dst.put_u32(len as u32);
+            dst.put_u16(HEADER_LENGTH as u16);
+            dst.put_u16(pk.proto_ver.to_u16());
+            dst.put_u32(pk.operation);
+            dst.put_u32(SEQUENCE_ID_DEFAULT);
+            dst.put(&pk.payload[..]);
+            Ok(())
+        }
+    }
+
+    impl Decoder for ChatCodec {
+        type Item = Vec<ChatPacket>;
+        type Error = CodecError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            // A frame may have no packets to deliver, e.g. when its messages all
+            // go to command handlers. `FramedRead` takes `None` as a request for
+            // more bytes, so move on to the next buffered frame instead.
+            loop {
+                if let Some(e) = self.pending_error.take() {
+                    return Err(e);
+                }
+                let src_len = src.len();
+                if src_len < HEADER_LENGTH {
+                    return Ok(None);
+                }
+                let mut cur = src.as_ref();
+                let len = cur.get_u32() as usize;
+                if len < HEADER_LENGTH {
+                    return Err(CodecError::InvalidHeader(len));
+                }
+                if src_len < len {
+                    // Reserved bytes counts from the current index
+                    src.reserve(len);
+                    return Ok(None);
+                }
+                let header_len = cur.get_u16() as usize;
+                let proto_ver = cur.get_u16();
+                let operation = cur.get_u32();
+                if header_len < HEADER_LENGTH || header_len > len {
+                    return Err(CodecError::InvalidHeader(header_len));
+                }
+                // The frame is consumed even if it fails to decode, so it can be skipped
+                let res = self.decode_frame(&src[..len], header_len, proto_ver, operation);
+                src.advance(len);
+                let res = res?;
+                if !res.is_empty() {
+                    return Ok(Some(res));
+                }
+            }
+        }
+
+        /// Fails if the socket is closed with a partial frame left, so that a
+        /// truncated connection isn't mistaken for a clean close.
+        fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            match self.decode(src)? {
+                Some(pks) => Ok(Some(pks)),
+                None if src.is_empty() => Ok(None),
+                None => Err(CodecError::UnexpectedEof { buffered: src.len() }),
+            }
+        }
+    }
+
+    impl ChatCodec {
+        /// Decodes the packets of a whole `frame`.
+        fn decode_frame(
+            &mut self,
+            frame: &[u8],
+            header_len: usize,
+            proto_ver: u16,
+            operation: u32,
+        ) -> Result<Vec<ChatPacket>, CodecError> {
+            // Skip by the header length the server sent rather than assuming it
+            let mut body = &frame[header_len..];
+
+            let mut res = Vec::new();
+            match operation {
+                OP_CONNECT_SUCCESS => res.push(ChatPacket::ConnectSuccess),
+                OP_HEARTBEAT_REPLY => {
+                    if body.len() < 4 {
+                        return Err(CodecError::ShortBuffer);
+                    }
+                    let value = body.get_u32();
+                    let extra = std::str::from_utf8(body)
+                        .ok()
+                        .filter(|s| !s.trim().is_empty())
+                        .and_then(|s| json::parse(s).ok());
+                    res.push(ChatPacket::Popularity(Popularity { value, extra }));
+                }
+                OP_MESSAGE => {
+                    let decompressed: Vec<u8>;
+                    let data = match ProtocolVersion::from_u16(proto_ver) {
+                        Some(ProtocolVersion::Plain) => frame,
+                        Some(ProtocolVersion::Zlib) => {
+                            decompressed = decompress_zlib(body, self.max_decompressed_size)?;
+                            &decompressed[..]
+                        }
+                        #[cfg(feature = "brotli")]
+                        Some(ProtocolVersion::Brotli) => {
+                            decompressed = decompress_brotli(body, self.max_decompressed_size)?;
+                            &decompressed[..]
+                        }
+                        _ => return Err(CodecError::UnsupportedProtocol(proto_ver)),
+                    };
+                    for body in BatchIter::new(data) {
+                        let str = body.and_then(|body| {
+                            std::str::from_utf8(body).map_err(|_| CodecError::InvalidUtf8)
+                        });
+                        let str = match str {
+                            Ok(str) => str,
+                            Err(e) if !res.is_empty() => {
+                                self.pending_error = Some(e);
+                                break;
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        // Invalid json ends up as a `ParsingError` like unexpected json
+                        let json = json::parse(str).unwrap_or(json::JsonValue::Null);
+                        if let (Some(recorder), false) = (&self.json_recorder, json.is_null()) {
+                            let _ = writeln!(recorder.lock().unwrap(), "{}", json.dump());
+                        }
+                        // Kept for the error, parsing consumes the json
+                        let cmd = json["cmd"].as_str().map(str::to_owned);
+                        let handle = cmd
+                            .as_deref()
+                            .filter(|cmd| !Message::known_commands().contains(cmd))
+                            .and_then(|cmd| self.commands.get(cmd));
+                        match handle {
+                            Some(handle) => (handle.lock().unwrap())(json),
+                            None => {
+                                let msg = Message::parse(json).unwrap_or_else(|| {
+                                    Message::ParsingError {
+                                        cmd,
+                                        raw: str.to_owned(),
+                                    }
+                                });
+                                res.push(ChatPacket::Message(msg));
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+            Ok(res)
+        }
+    }
+
+    /// Splits a batch of packets, yielding the body of each packet.
+    ///
+    /// Each packet is only framed when it's reached, so the packets before a
+    /// malformed one are still delivered.
+    struct BatchIter<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> BatchIter<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Self { data }
+        }
+    }
+
+    impl<'a> Iterator for BatchIter<'a> {
+        type Item = Result<&'a [u8], CodecError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.data.is_empty() {
+                return None;
+            }
+            let len = match self.data {
+                [a, b, c, d, ..] => u32::from_be_bytes([*a, *b, *c, *d]) as usize,
+                _ => 0,
+            };
+            if len < HEADER_LENGTH || len > self.data.len() {
+                // The rest can't be framed, stop after reporting it
+                self.data = &[];
+                return Some(Err(CodecError::ShortBuffer));
+            }
+            let (pk, rest) = self.data.split_at(len);
+            self.data = rest;
+            Some(Ok(&pk[HEADER_LENGTH..]))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::msg::tests::{DANMU_MSG, SEND_GIFT, SUPER_CHAT_MESSAGE};
+        use miniz_oxide::deflate::compress_to_vec_zlib;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use hyper::{Body, Response};
+        use std::net::Shutdown;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        /// Builds a frame the way the server does.
+        fn frame(proto_ver: u16, operation: u32, body: &[u8]) -> Vec<u8> {
+            let mut buf = Vec::new();
+            buf.put_u32((HEADER_LENGTH + body.len()) as u32);
+            buf.put_u16(HEADER_LENGTH as u16);
+            buf.put_u16(proto_ver);
+            buf.put_u32(operation);
+            buf.put_u32(SEQUENCE_ID_DEFAULT);
+            buf.extend_from_slice(body);
+            buf
+        }
+
+        fn message(cmd: &str) -> Vec<u8> {
+            frame(0, OP_MESSAGE, format!(r#"{{"cmd":"{}"}}"#, cmd).as_bytes())
+        }
+
+        fn json_message(json: &str) -> Vec<u8> {
+            frame(0, OP_MESSAGE, json.as_bytes())
+        }
+
+        fn decode_all(codec: &mut ChatCodec, data: &[u8]) -> Result<Vec<ChatPacket>, CodecError> {
+            let mut src = BytesMut::from(data);
+            let mut pks = Vec::new();
+            while let Some(next) = codec.decode_eof(&mut src)? {
+                pks.extend(next);
+            }
+            Ok(pks)
+        }
+
+        fn decode(data: &[u8]) -> Result<Vec<ChatPacket>, CodecError> {
+            decode_all(&mut ChatCodec::default(), data)
+        }
+
+        fn is_message(pk: &ChatPacket, expected: fn(&Message) -> bool) -> bool {
+            matches!(pk, ChatPacket::Message(msg) if expected(msg))
+        }
+
+        fn is_live(pks: &[ChatPacket]) -> bool {
+            matches!(pks, [pk] if is_message(pk, |msg| matches!(msg, Message::Live)))
+        }
+
+        #[test]
+        fn maps_protocol_versions() {
+            let versions = [
+                (0, ProtocolVersion::Plain),
+                (1, ProtocolVersion::Heartbeat),
+                (2, ProtocolVersion::Zlib),
+                (3, ProtocolVersion::Brotli),
+            ];
+            for &(n, version) in &versions {
+                assert_eq!(ProtocolVersion::from_u16(n), Some(version));
+                assert_eq!(version.to_u16(), n);
+            }
+            assert_eq!(ProtocolVersion::from_u16(4), None);
+        }
+
+        #[test]
+        fn decodes_connect_success() {
+            let pks = decode(&frame(1, OP_CONNECT_SUCCESS, b"{\"code\":0}")).unwrap();
+            assert!(matches!(pks[..], [ChatPacket::ConnectSuccess]));
+        }
+
+        #[test]
+        fn decodes_heartbeat_reply() {
+            let mut body = 1234u32.to_be_bytes().to_vec();
+            let pks = decode(&frame(1, OP_HEARTBEAT_REPLY, &body)).unwrap();
+            assert!(matches!(
+                &pks[..],
+                [ChatPacket::Popularity(Popularity { value: 1234, extra: None })]
+            ));
+
+            body.extend_from_slice(br#"{"count":5}"#);
+            let pks = decode(&frame(1, OP_HEARTBEAT_REPLY, &body)).unwrap();
+            match &pks[..] {
+                [ChatPacket::Popularity(Popularity { value: 1234, extra: Some(extra) })] => {
+                    assert_eq!(extra["count"], 5)
+                }
+                _ => panic!("expected a popularity packet"),
+            }
+        }
+
+        #[test]
+        fn decodes_plain_message() {
+            assert!(is_live(&decode(&message("LIVE")).unwrap()));
+        }
+
+        #[test]
+        fn decodes_zlib_batch() {
+            let batch = [message("LIVE"), message("PREPARING")].concat();
+            let data = frame(2, OP_MESSAGE, &compress_to_vec_zlib(&batch, 6));
+            let pks = decode(&data).unwrap();
+            assert_eq!(pks.len(), 2);
+            assert!(is_message(&pks[0], |msg| matches!(msg, Message::Live)));
+            assert!(is_message(&pks[1], |msg| matches!(msg, Message::Preparing)));
+        }
+
+        #[test]
+        fn decodes_danmaku_frame() {
+            let pks = decode(&json_message(DANMU_MSG)).unwrap();
+            match &pks[..] {
+                [ChatPacket::Message(Message::Danmaku { uid, uname, text, .. })] => {
+                    assert_eq!(*uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                    assert_eq!(text, "晚上好");
+                }
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn decodes_zlib_batch_of_messages() {
+            let batch = [
+                json_message(DANMU_MSG),
+                json_message(SEND_GIFT),
+                json_message(SUPER_CHAT_MESSAGE),
+            ]
+            .concat();
+            let data = frame(2, OP_MESSAGE, &compress_to_vec_zlib(&batch, 6));
+            let pks = decode(&data).unwrap();
+            assert_eq!(pks.len(), 3);
+            assert!(is_message(&pks[0], |msg| matches!(msg, Message::Danmaku { .. })));
+            assert!(is_message(&pks[1], |msg| matches!(msg, Message::SendGift { num: 10, .. })));
+            assert!(is_message(&pks[2], |msg| matches!(msg, Message::SuperChatMessage { .. })));
+        }
+
+        #[test]
+        fn reports_the_command_of_malformed_messages() {
+            let malformed = r#"{"cmd":"SEND_GIFT","data":{"giftName":"小花花"}}"#;
+            let pks = decode(&json_message(malformed)).unwrap();
+            match &pks[..] {
+                [ChatPacket::Message(Message::ParsingError { cmd, raw })] => {
+                    assert_eq!(cmd.as_deref(), Some("SEND_GIFT"));
+                    assert_eq!(raw, malformed);
+                }
+                _ => panic!("expected a parsing error"),
+            }
+
+            let pks = decode(&json_message(r#"{"cmd":"SEND_GIFT","#)).unwrap();
+            match &pks[..] {
+                [ChatPacket::Message(Message::ParsingError { cmd, .. })] => assert_eq!(*cmd, None),
+                _ => panic!("expected a parsing error"),
+            }
+        }
+
+        #[test]
+        fn reassembles_partial_frames() {
+            let data = [message("LIVE"), frame(1, OP_CONNECT_SUCCESS, b"")].concat();
+            let mut codec = ChatCodec::default();
+            let mut src = BytesMut::new();
+            let mut pks = Vec::new();
+            for chunk in data.chunks(5) {
+                src.extend_from_slice(chunk);
+                while let Some(next) = codec.decode(&mut src).unwrap() {
+                    pks.extend(next);
+                }
+            }
+            assert!(src.is_empty());
+            assert_eq!(pks.len(), 2);
+            assert!(is_message(&pks[0], |msg| matches!(msg, Message::Live)));
+            assert!(matches!(pks[1], ChatPacket::ConnectSuccess));
+        }
+
+        #[test]
+        fn skips_frames_without_packets() {
+            let handled = Arc::new(AtomicUsize::new(0));
+            let counter = handled.clone();
+            let options = ConnectOptions::default().register_command("CUSTOM", move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            let mut codec = ChatCodec::new(&options);
+            let mut src = BytesMut::from(&[message("CUSTOM"), message("LIVE")].concat()[..]);
+            assert!(is_live(&codec.decode(&mut src).unwrap().unwrap()));
+            assert_eq!(handled.load(Ordering::SeqCst), 1);
+            assert!(src.is_empty());
+        }
+
+        #[test]
+        fn splits_batches() {
+            let data = [message("LIVE"), message("PREPARING")].concat();
+            let bodies: Vec<_> = BatchIter::new(&data).map(Result::unwrap).collect();
+            assert_eq!(bodies, [&br#"{"cmd":"LIVE"}"#[..], br#"{"cmd":"PREPARING"}"#]);
+
+            // The truncated packet isn't looked at until it's reached
+            let mut iter = BatchIter::new(&data[..data.len() - 1]);
+            assert_eq!(iter.next().unwrap().unwrap(), br#"{"cmd":"LIVE"}"#);
+            assert!(matches!(iter.next(), Some(Err(CodecError::ShortBuffer))));
+            assert!(iter.next().is_none());
+        }
+
+        #[test]
+        fn delivers_packets_before_a_malformed_one() {
+            let malformed = frame(0, OP_MESSAGE, b"\xff\xfe");
+            let batch = [message("LIVE"), malformed, message("PREPARING")].concat();
+            let data = frame(2, OP_MESSAGE, &compress_to_vec_zlib(&batch, 6));
+            let mut codec = ChatCodec::default();
+            let mut src = BytesMut::from(&data[..]);
+            assert!(is_live(&codec.decode(&mut src).unwrap().unwrap()));
+            assert!(matches!(codec.decode(&mut src), Err(CodecError::InvalidUtf8)));
+            assert!(matches!(codec.decode_eof(&mut src), Ok(None)));
+        }
+
+        #[test]
+        fn caps_zlib_output() {
+            let data = vec![0; 4096];
+            let compressed = compress_to_vec_zlib(&data, 6);
+            assert_eq!(decompress_zlib(&compressed, 4096).unwrap(), data);
+            let res = decompress_zlib(&compressed, 4095);
+            assert!(matches!(res, Err(CodecError::OversizedFrame(4095))));
+            assert!(matches!(decompress_zlib(b"not zlib", 4096), Err(CodecError::Decompress)));
+
+            let mut codec = ChatCodec::new(&ConnectOptions::default().max_decompressed_size(1024));
+            let res = decode_all(&mut codec, &frame(2, OP_MESSAGE, &compressed));
+            assert!(matches!(res, Err(CodecError::OversizedFrame(1024))));
+        }
+
+        #[cfg(feature = "brotli")]
+        fn compress_brotli(data: &[u8]) -> Vec<u8> {
+            use std::io::Read;
+
+            let mut compressed = Vec::new();
+            brotli::CompressorReader::new(data, 4096, 5, 22)
+                .read_to_end(&mut compressed)
+                .unwrap();
+            compressed
+        }
+
+        #[cfg(feature = "brotli")]
+        #[test]
+        fn decodes_brotli_batch() {
+            let mut second = json::parse(DANMU_MSG).unwrap();
+            second["info"][1] = "第二条".into();
+            let batch = [json_message(DANMU_MSG), json_message(&second.dump())].concat();
+            let data = frame(3, OP_MESSAGE, &compress_brotli(&batch));
+            let texts: Vec<_> = decode(&data)
+                .unwrap()
+                .into_iter()
+                .map(|pk| match pk {
+                    ChatPacket::Message(Message::Danmaku { text, .. }) => text,
+                    _ => panic!("expected a danmaku"),
+                })
+                .collect();
+            assert_eq!(texts, ["晚上好", "第二条"]);
+        }
+
+        #[cfg(feature = "brotli")]
+        #[test]
+        fn caps_brotli_output() {
+            let data = vec![0; 4096];
+            let compressed = compress_brotli(&data);
+            assert_eq!(decompress_brotli(&compressed, 4096).unwrap(), data);
+            let res = decompress_brotli(&compressed, 4095);
+            assert!(matches!(res, Err(CodecError::OversizedFrame(4095))));
+        }
+
+        #[test]
+        fn fails_on_eof_mid_frame() {
+            let data = message("LIVE");
+            let mut codec = ChatCodec::default();
+            let mut src = BytesMut::from(&data[..data.len() - 1]);
+            assert!(matches!(codec.decode(&mut src), Ok(None)));
+            let res = codec.decode_eof(&mut src);
+            let buffered = data.len() - 1;
+            assert!(matches!(res, Err(CodecError::UnexpectedEof { buffered: n }) if n == buffered));
+
+            let res = codec.decode_eof(&mut BytesMut::from(&data[..4]));
+            assert!(matches!(res, Err(CodecError::UnexpectedEof { buffered: 4 })));
+            assert!(matches!(codec.decode_eof(&mut BytesMut::new()), Ok(None)));
+        }
+
+        #[test]
+        fn reports_bad_frames() {
+            let decode = |data: &[u8]| decode(data).err().unwrap();
+
+            let mut short_len = frame(0, OP_MESSAGE, b"");
+            short_len[..4].copy_from_slice(&8u32.to_be_bytes());
+            let e = decode(&short_len);
+            assert!(matches!(e, CodecError::InvalidHeader(8)) && !e.is_skippable());
+
+            let mut long_header = frame(0, OP_MESSAGE, b"");
+            long_header[4..6].copy_from_slice(&32u16.to_be_bytes());
+            let e = decode(&long_header);
+            assert!(matches!(e, CodecError::InvalidHeader(32)) && !e.is_skippable());
+
+            let e = decode(&frame(7, OP_MESSAGE, b"{}"));
+            assert!(matches!(e, CodecError::UnsupportedProtocol(7)) && e.is_skippable());
+
+            let e = decode(&frame(1, OP_HEARTBEAT_REPLY, b"\0\0"));
+            assert!(matches!(e, CodecError::ShortBuffer) && e.is_skippable());
+
+            let e = decode(&frame(2, OP_MESSAGE, b"not zlib"));
+            assert!(matches!(e, CodecError::Decompress) && e.is_skippable());
+
+            let e = decode(&frame(0, OP_MESSAGE, b"\xff\xfe"));
+            assert!(matches!(e, CodecError::InvalidUtf8) && e.is_skippable());
+        }
+
+        #[test]
+        fn skips_bad_frame_and_decodes_the_next() {
+            let data = [frame(7, OP_MESSAGE, b"{}"), message("LIVE")].concat();
+            let mut codec = ChatCodec::default();
+            let mut src = BytesMut::from(&data[..]);
+            assert!(codec.decode(&mut src).is_err());
+            assert!(is_live(&codec.decode(&mut src).unwrap().unwrap()));
+        }
+
+        #[tokio::test]
+        async fn decodes_ws_messages() {
+            let data = [frame(1, OP_CONNECT_SUCCESS, b""), message("LIVE")].concat();
+            let (first, rest) = data.split_at(20);
+            let msgs = vec![
+                Ok(WsMessage::Binary(first.to_vec())),
+                Ok(WsMessage::Ping(Vec::new())),
+                Ok(WsMessage::Binary(rest.to_vec())),
+                Ok(WsMessage::Binary(data[..10].to_vec())),
+            ];
+            let pks = ws_packets(futures_util::stream::iter(msgs), ChatCodec::default());
+            let res: Vec<_> = pks.collect().await;
+            assert_eq!(res.len(), 3);
+            assert!(matches!(res[0].as_ref().unwrap()[..], [ChatPacket::ConnectSuccess]));
+            assert!(is_live(res[1].as_ref().unwrap()));
+            assert!(matches!(res[2], Err(CodecError::UnexpectedEof { buffered: 10 })));
+        }
+
+        #[tokio::test]
+        async fn connects_to_a_websocket_server() {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/sub", listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let auth = match ws.next().await {
+                    Some(Ok(WsMessage::Binary(auth))) => auth,
+                    _ => panic!("expected a binary message"),
+                };
+                let reply = frame(1, OP_CONNECT_SUCCESS, br#"{"code":0}"#);
+                ws.send(WsMessage::Binary(reply)).await.unwrap();
+                auth
+            });
+
+            let mut conn = Connection::WebSocket(Box::new(connect_ws(&url).await.unwrap()));
+            let (mut r, mut w) = conn.split(&ConnectOptions::default());
+            w.send(RawChatPacket::authenticate(1, "token".to_owned())).await.unwrap();
+            assert!(matches!(r.next().await.unwrap().unwrap()[..], [ChatPacket::ConnectSuccess]));
+
+            let auth = server.await.unwrap();
+            let mut expected = BytesMut::new();
+            ChatCodec::default()
+                .encode(RawChatPacket::authenticate(1, "token".to_owned()), &mut expected)
+                .unwrap();
+            assert_eq!(auth, expected);
+        }
+
+        #[test]
+        fn picks_hosts_by_weight() {
+            use rand::rngs::StdRng;
+            use rand::SeedableRng;
+
+            let hosts = vec![
+                ("a".to_owned(), 2243, 1),
+                ("b".to_owned(), 2243, 3),
+                ("c".to_owned(), 2243, 0),
+            ];
+            let mut rng = StdRng::seed_from_u64(694);
+            let mut picks = [0; 3];
+            for _ in 0..10_000 {
+                picks[pick_weighted(&hosts, &mut rng).unwrap()] += 1;
+            }
+            assert_eq!(picks[2], 0);
+            // Expecting 2500 and 7500
+            assert!((2300..2700).contains(&picks[0]), "{:?}", picks);
+            assert!((7300..7700).contains(&picks[1]), "{:?}", picks);
+
+            assert!(pick_weighted(&[("a".to_owned(), 2243, 0)], &mut rng).is_err());
+        }
+
+        #[tokio::test]
+        async fn fails_over_to_the_next_host() {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            // Nothing listens on a port just released
+            let dead_port = {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                listener.local_addr().unwrap().port()
+            };
+
+            let hosts = vec![
+                ("127.0.0.1".to_owned(), dead_port, 1000),
+                ("127.0.0.1".to_owned(), port, 1),
+            ];
+            let (stream, accepted) = tokio::join!(connect_weighted(hosts), listener.accept());
+            let (accepted, _) = accepted.unwrap();
+            assert_eq!(stream.unwrap().peer_addr().unwrap(), accepted.local_addr().unwrap());
+
+            let hosts = vec![("127.0.0.1".to_owned(), dead_port, 1)];
+            assert!(connect_weighted(hosts).await.is_err());
+        }
+
+        fn reader(
+            batches: Vec<Result<Vec<ChatPacket>, CodecError>>,
+        ) -> PacketReader<impl Stream<Item=Result<Vec<ChatPacket>, CodecError>> + Unpin> {
+            let batches = futures_util::stream::iter(batches);
+            PacketReader::new(batches, &ConnectOptions::default(), None)
+        }
+
+        #[tokio::test]
+        async fn times_out_when_the_server_goes_silent() {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (close_tx, close_rx) = oneshot::channel::<()>();
+            tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let success = frame(1, OP_CONNECT_SUCCESS, b"{\"code\":0}");
+                socket.write_all(&success).await.unwrap();
+                // Keep the socket open without writing anything else
+                let _ = close_rx.await;
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let options = ConnectOptions::default().idle_timeout(Duration::from_millis(100));
+            let stream = FramedRead::new(stream, ChatCodec::default());
+            let mut r = PacketReader::new(stream, &options, None);
+            assert!(matches!(r.next().await.unwrap().unwrap()[..], [ChatPacket::ConnectSuccess]));
+
+            let started = Instant::now();
+            let e = match r.next().await {
+                Err(e) => e,
+                Ok(_) => panic!("expected an idle timeout"),
+            };
+            assert!(started.elapsed() >= Duration::from_millis(100));
+            assert_eq!(
+                e.downcast_ref::<TimeoutError>(),
+                Some(&TimeoutError::Idle(Duration::from_millis(100))),
+            );
+            drop(close_tx);
+        }
+
+        #[tokio::test]
+        async fn times_out_waiting_for_connect_success() {
+            let options = ConnectOptions::default()
+                .handshake_timeout(Duration::from_millis(50))
+                .idle_timeout(Duration::from_secs(60));
+            let silent = futures_util::stream::pending();
+            let mut r = PacketReader::new(silent, &options, None);
+            let e = match r.next().await {
+                Err(e) => e,
+                Ok(_) => panic!("expected a handshake timeout"),
+            };
+            assert_eq!(e.downcast_ref::<TimeoutError>(), Some(&TimeoutError::Handshake));
+
+            // Packets other than `ConnectSuccess` don't end the handshake
+            let batches = futures_util::stream::iter(vec![Ok(lives(1))])
+                .chain(futures_util::stream::pending());
+            let mut r = PacketReader::new(batches, &options, None);
+            assert_eq!(r.next().await.unwrap().unwrap().len(), 1);
+            let e = match r.next().await {
+                Err(e) => e,
+                Ok(_) => panic!("expected a handshake timeout"),
+            };
+            assert_eq!(e.downcast_ref::<TimeoutError>(), Some(&TimeoutError::Handshake));
+        }
+
+        /// Runs `handle_sink` for a while and returns the operations it wrote.
+        async fn sent_operations(send_heartbeats: bool) -> Vec<u32> {
+            let mut sent = Vec::new();
+            let sink = (&mut sent).sink_map_err(|e| match e {});
+            let handled = handle_sink(sink, 1, "token".to_owned(), send_heartbeats, None);
+            assert!(time::timeout(Duration::from_millis(50), handled).await.is_err());
+            sent.iter().map(|pk: &RawChatPacket| pk.operation).collect()
+        }
+
+        #[tokio::test]
+        async fn sends_heartbeats_only_when_enabled() {
+            let sent = sent_operations(false).await;
+            assert_eq!(sent, [OP_USER_AUTHENTICATION]);
+
+            let sent = sent_operations(true).await;
+            assert_eq!(sent, [OP_USER_AUTHENTICATION, OP_HEARTBEAT]);
+        }
+
+        /// Options resolving any room through a local API and connecting to a
+        /// local server, which writes `frames` after the auth packet then closes
+        /// if `close` is set. Otherwise it waits for the client to close.
+        async fn mock_room(frames: Vec<Vec<u8>>, close: bool) -> ConnectOptions {
+            let api = http_api::tests::serve(|req| {
+                let body = match req.uri().path() {
+                    "/room/v1/Room/room_init" => r#"{"code":0,"data":{"room_id":1001}}"#,
+                    "/room/v1/Danmu/getConf" => r#"{"code":0,"data":{"token":"token"}}"#,
+                    _ => r#"{"code":-404,"message":"not found"}"#,
+                };
+                Response::new(Body::from(body))
+            });
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut header = [0; HEADER_LENGTH];
+                socket.read_exact(&mut header).await.unwrap();
+                let len = (&header[..]).get_u32() as usize;
+                socket.read_exact(&mut vec![0; len - HEADER_LENGTH]).await.unwrap();
+                for frame in frames {
+                    socket.write_all(&frame).await.unwrap();
+                    time::delay_for(Duration::from_millis(10)).await;
+                }
+                if close {
+                    socket.shutdown(Shutdown::Write).unwrap();
+                }
+                // Read what the client still sends until it closes
+                let _ = socket.read_to_end(&mut Vec::new()).await;
+            });
+            ConnectOptions::default()
+                .api_url(&api)
+                .hosts(vec![("127.0.0.1".to_owned(), port, 1)])
+        }
+
+        fn connect_success() -> Vec<u8> {
+            frame(1, OP_CONNECT_SUCCESS, b"{\"code\":0}")
+        }
+
+        #[tokio::test]
+        async fn follows_the_connection_state() {
+            let options = mock_room(vec![connect_success(), message("LIVE")], true).await;
+            let (mut state, conn) = connect_with_handle(1, options, |_| async {});
+            assert_eq!(*state.borrow(), ConnectionState::Connecting);
+            let states = tokio::spawn(async move {
+                let mut states = Vec::new();
+                while let Some(s) = state.recv().await {
+                    states.push(s);
+                }
+                states
+            });
+            conn.await.unwrap();
+            assert_eq!(
+                states.await.unwrap(),
+                [
+                    ConnectionState::Connecting,
+                    ConnectionState::Authenticating,
+                    ConnectionState::Connected,
+                    ConnectionState::Disconnected,
+                ],
+            );
+        }
+
+        /// Runs a session with a local server, recording into a log of `capacity`.
+        async fn logged_session(capacity: usize) -> Vec<Event> {
+            let log = EventLog::new(capacity);
+            let options = mock_room(vec![connect_success(), message("LIVE")], true).await;
+            let options = options.event_log(log.clone());
+            connect_with_options(1, options, |_| async {}).await.unwrap();
+            log.snapshot()
+        }
+
+        #[tokio::test]
+        async fn logs_the_events_of_a_session() {
+            let events = logged_session(16).await;
+            assert!(matches!(
+                events.iter().map(|e| &e.kind).collect::<Vec<_>>()[..],
+                [
+                    EventKind::Connect,
+                    EventKind::Authenticate,
+                    EventKind::Heartbeat,
+                    EventKind::Disconnect,
+                ],
+            ));
+            assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+
+            let events = logged_session(2).await;
+            assert!(matches!(
+                events.iter().map(|e| &e.kind).collect::<Vec<_>>()[..],
+                [EventKind::Heartbeat, EventKind::Disconnect],
+            ));
+
+            assert!(logged_session(0).await.is_empty());
+        }
+
+        #[tokio::test]
+        async fn delivers_packets_through_a_channel() {
+            let options = mock_room(vec![connect_success(), message("LIVE")], true).await;
+            let (handle, mut rx) = spawn_connect(1, options);
+            assert!(matches!(rx.recv().await, Some(ChatPacket::ConnectSuccess)));
+            assert!(matches!(rx.recv().await, Some(ChatPacket::Message(Message::Live))));
+            handle.await.unwrap().unwrap();
+            assert!(rx.recv().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn closes_the_connection_once_the_receiver_is_dropped() {
+            let options = mock_room(vec![connect_success(), message("LIVE")], false).await;
+            let (handle, mut rx) = spawn_connect(1, options);
+            assert!(matches!(rx.recv().await, Some(ChatPacket::ConnectSuccess)));
+            drop(rx);
+            // The server keeps the connection open, so only the receiver can end it
+            let res = time::timeout(Duration::from_secs(5), handle).await;
+            res.expect("the connection stayed open").unwrap().unwrap();
+        }
+
+        #[tokio::test]
+        async fn connects_through_a_client() {
+            let custom = Arc::new(AtomicUsize::new(0));
+            let log = EventLog::new(16);
+            let frames = vec![connect_success(), message("CUSTOM_CMD"), message("LIVE")];
+            let c = custom.clone();
+            let client = ChatClient::new(
+                mock_room(frames, true)
+                    .await
+                    .handler_concurrency(2)
+                    .send_heartbeats(false)
+                    .register_command("CUSTOM_CMD", move |json| {
+                        assert_eq!(json["cmd"], "CUSTOM_CMD");
+                        c.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .event_log(log.clone()),
+            );
+
+            let (handler, handled, _) = counting_handler();
+            client.connect(1, handler).await.unwrap();
+            // The custom command went to its handler instead
+            assert_eq!(handled.load(Ordering::SeqCst), 2);
+            assert_eq!(custom.load(Ordering::SeqCst), 1);
+            let events = log.snapshot();
+            assert!(matches!(
+                events.iter().map(|e| &e.kind).collect::<Vec<_>>()[..],
+                [EventKind::Connect, EventKind::Authenticate, EventKind::Disconnect],
+            ));
+        }
+
+        #[tokio::test]
+        async fn delivers_the_packets_of_a_frame_at_once() {
+            let batch = [json_message(DANMU_MSG), message("LIVE"), message("PREPARING")].concat();
+            let frames = vec![
+                connect_success(),
+                frame(2, OP_MESSAGE, &compress_to_vec_zlib(&batch, 6)),
+                message("LIVE"),
+            ];
+            let options = mock_room(frames, true).await;
+            let mut batches = Vec::new();
+            connect_batched_with_options(1, options, |pks| {
+                batches.push(pks);
+                async {}
+            })
+            .await
+            .unwrap();
+
+            assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), [1, 3, 1]);
+            assert!(matches!(batches[0][..], [ChatPacket::ConnectSuccess]));
+            assert!(is_message(&batches[1][0], |msg| matches!(msg, Message::Danmaku { .. })));
+            assert!(is_message(&batches[1][1], |msg| matches!(msg, Message::Live)));
+            assert!(is_message(&batches[1][2], |msg| matches!(msg, Message::Preparing)));
+            assert!(is_live(&batches[2]));
+        }
+
+        #[tokio::test]
+        async fn polls_the_online_count() {
+            let polls = AtomicUsize::new(0);
+            let api = http_api::tests::serve(move |req| {
+                assert_eq!(req.uri().path(), "/online");
+                let n = polls.fetch_add(1, Ordering::SeqCst) + 1;
+                let body = format!(r#"{{"code":0,"data":{{"onlineNum":{}}}}}"#, n * 100);
+                Response::new(Body::from(body))
+            });
+            let interval = Duration::from_millis(50);
+            let options = mock_room(vec![connect_success()], false)
+                .await
+                .online_count(&format!("{}/online", api), interval);
+
+            let started = Instant::now();
+            let (_handle, mut rx) = spawn_connect(1, options);
+            let mut counts = Vec::new();
+            while counts.len() < 3 {
+                match rx.recv().await {
+                    Some(ChatPacket::OnlineCount(count)) => counts.push(count),
+                    Some(_) => {}
+                    None => panic!("the connection ended"),
+                }
+            }
+            assert_eq!(counts, [100, 200, 300]);
+            // The first poll is made right away
+            assert!(started.elapsed() >= interval * 2);
+        }
+
+        #[tokio::test]
+        async fn records_messages_as_jsonl() {
+            let path = std::env::temp_dir().join(format!("mira-{}.jsonl", std::process::id()));
+            let file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+            let batch = [json_message(DANMU_MSG), json_message(SEND_GIFT)].concat();
+            let frames = vec![
+                connect_success(),
+                frame(2, OP_MESSAGE, &compress_to_vec_zlib(&batch, 6)),
+                message("LIVE"),
+            ];
+            let options = mock_room(frames, true).await.record_json(file);
+            let mut pks = Vec::new();
+            connect_with_options(1, options, |pk| {
+                pks.push(pk);
+                async {}
+            })
+            .await
+            .unwrap();
+
+            // The packets are delivered unchanged
+            assert_eq!(pks.len(), 4);
+            assert!(is_message(&pks[1], |msg| matches!(msg, Message::Danmaku { .. })));
+            assert!(is_message(&pks[2], |msg| matches!(msg, Message::SendGift { .. })));
+            assert!(is_message(&pks[3], |msg| matches!(msg, Message::Live)));
+
+            let recorded = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let lines: Vec<_> = recorded.lines().map(|line| json::parse(line).unwrap()).collect();
+            assert_eq!(lines.len(), 3);
+            assert_eq!(lines[0], json::parse(DANMU_MSG).unwrap());
+            assert_eq!(lines[1], json::parse(SEND_GIFT).unwrap());
+            assert_eq!(lines[2]["cmd"], "LIVE");
+        }
+
+        fn lives(n: usize) -> Vec<ChatPacket> {
+            (0..n).map(|_| ChatPacket::Message(Message::Live)).collect()
+        }
+
+        type Handled = Pin<Box<dyn Future<Output=()>>>;
+
+        /// A handler counting the packets handled and the most run at once.
+        fn counting_handler()
+            -> (impl FnMut(ChatPacket) -> Handled, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+            let handled = Arc::new(AtomicUsize::new(0));
+            let max_running = Arc::new(AtomicUsize::new(0));
+            let running = Arc::new(AtomicUsize::new(0));
+            let (h, m) = (handled.clone(), max_running.clone());
+            let handler = move |_| {
+                let (handled, max_running, running) = (h.clone(), m.clone(), running.clone());
+                Box::pin(async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    time::delay_for(Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    handled.fetch_add(1, Ordering::SeqCst);
+                }) as Handled
+            };
+            (handler, handled, max_running)
+        }
+
+        #[tokio::test]
+        async fn runs_handlers_concurrently() {
+            let (handler, handled, max_running) = counting_handler();
+            let (_sink_failed_tx, sink_failed) = oneshot::channel();
+            let reader = reader(vec![Ok(lives(5)), Ok(lives(3))]);
+            let res = handle_stream(reader, 3, handler, sink_failed).await;
+            assert!(res.is_ok());
+            assert_eq!(handled.load(Ordering::SeqCst), 8);
+            assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        }
+
+        #[tokio::test]
+        async fn finishes_handlers_before_a_read_error() {
+            let (handler, handled, _) = counting_handler();
+            let (_sink_failed_tx, sink_failed) = oneshot::channel();
+            let batches = vec![Ok(lives(4)), Err(CodecError::InvalidHeader(0))];
+            let res = handle_stream(reader(batches), 2, handler, sink_failed).await;
+            assert!(res.unwrap_err().downcast_ref::<CodecError>().is_some());
+            assert_eq!(handled.load(Ordering::SeqCst), 4);
+        }
+
+        #[tokio::test]
+        async fn drains_received_packets_after_a_write_failure() {
+            for &concurrency in &[1, 2] {
+                let (handler, handled, _) = counting_handler();
+                let (sink_failed_tx, sink_failed) = oneshot::channel();
+                // The socket stays open, only the write side fails
+                let batches = futures_util::stream::iter(vec![Ok(lives(2)), Ok(lives(3))])
+                    .chain(futures_util::stream::pending());
+                let reader = PacketReader::new(batches, &ConnectOptions::default(), None);
+                let res = drive(
+                    handle_stream(reader, concurrency, handler, sink_failed),
+                    async { Err(anyhow::anyhow!("write failed")) },
+                    sink_failed_tx,
+                )
+                .await;
+                assert_eq!(res.unwrap_err().to_string(), "write failed");
+                assert_eq!(handled.load(Ordering::SeqCst), 5);
+            }
+        }
+    }
+}
+
+pub mod msg {
+    use self::Message::*;
+    use std::fmt;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// Color of danmaku without a meaningful text color, such as stickers
+    const DEFAULT_DANMAKU_COLOR: u32 = 0xFFFFFF;
+
+    /// Every `cmd` that `Message::parse` understands.
+    ///
+    /// Keep in sync with the match arms of `Message::parse`, which a test checks.
+    const KNOWN_COMMANDS: &[&str] = &[
+        "PREPARING",
+        "LIVE",
+        "ROOM_CHANGE",
+        "DANMU_MSG",
+        "SEND_GIFT",
+        "COMBO_END",
+        "WELCOME",
+        "WELCOME_GUARD",
+        "ROOM_RANK",
+        "ENTRY_EFFECT",
+        "NOTICE_MSG",
+        "ROOM_REAL_TIME_MESSAGE_UPDATE",
+        "SUPER_CHAT_MESSAGE",
+        "SUPER_CHAT_MESSAGE_JPN",
+        "SUPER_CHAT_ENTRANCE",
+        "HOT_ROOM_NOTIFY",
+        "GOTO_BUY_FLOW",
+        "HOT_BUY_NUM",
+        "RECOMMEND_CARD",
+        "SHOPPING_CART_SHOW",
+        "LOG_IN_NOTICE",
+        "DANMU_GIFT_LOTTERY_AWARD",
+        "WIN_ACTIVITY",
+        "RING_STATUS_CHANGE",
+        "VTR_GIFT",
+        "LIVE_OPEN_PLATFORM_LOTTERY",
+        "LIVE_MULTI_VIEW_INFO",
+        "GUARD_HONOR_THOUSAND",
+        "ACTIVITY_BANNER_UPDATE_V2",
+        "POPULARITY_RED_POCKET_NEW",
+        "LIVE_OPEN_PLATFORM_DM",
+        "DM_INTERACTION",
+        "POPULAR_RANK_GUIDE_CARD",
+        "ROOM_MODULE_UPDATE",
+        "GIFT_STAR_PROCESS",
+        "ONLINE_RANK_COUNT",
+        "WEALTH_NOTIFY",
+        "PLAY_TOGETHER",
+        "CHANGE_ROOM_INFO",
+        "USER_TOAST_MSG",
+        "VIDEO_CONNECTION_JOIN_START",
+        "VIDEO_CONNECTION_JOIN_END",
+        "LIVE_OPEN_PLATFORM_SUPER_CHAT",
+        "WATCHED_CHANGE",
+        "ROOM_SILENT_ON",
+        "POPULARITY_RED_POCKET_START",
+    ];
+
+    /// A message sent by the server.
+    ///
+    /// New commands are added over time, so matching on it needs a wildcard arm.
+    #[non_exhaustive]
     pub enum Message {
         /// 结束直播
         Preparing,
@@ -263,6 +2707,22 @@ pub mod msg {
             r#type: u32,
             uid: u32,
             uname: String,
+            /// 被 @ 的用户 uid，来自 `extra` 中的 `reply_mid`
+            mentions: Vec<u32>,
+            /// 发送时间（毫秒），没有时为 0
+            sent_at: u64,
+            /// 语音弹幕转换成的文字
+            recognized_text: Option<String>,
+            /// 用户等级
+            user_level: Option<u32>,
+            /// 舰队徽章图标
+            guard_icon: Option<String>,
+            /// 天选时刻抽奖弹幕
+            is_lottery: bool,
+            /// 签到弹幕
+            is_checkin: bool,
+            /// 官方认证账号
+            is_official: bool,
         },
         /// 礼物
         SendGift {
@@ -271,6 +2731,10 @@ pub mod msg {
             num: u32,
             uid: u32,
             uname: String,
+            /// 瓜子类型，`gold` 或 `silver`，没有时为空
+            coin_type: String,
+            /// 总价值（瓜子），没有时为 0
+            total_coin: u32,
         },
         /// 礼物连击结束
         ComboEnd {
@@ -300,6 +2764,8 @@ pub mod msg {
         RoomRealTimeMessageUpdate {
             /// 粉丝数
             fans: u32,
+            /// 粉丝团人数，没有时为 0
+            fans_club: u32,
         },
         /// 房间排行榜
         RoomRank {
@@ -315,6 +2781,8 @@ pub mod msg {
             face: String,
             copy_writing: String,
             copy_color: String,
+            /// 荣耀等级，由荣耀等级触发的进场效果才有
+            wealth_level: Option<u32>,
         },
         /// 通知消息
         NoticeMessage {
@@ -322,6 +2790,10 @@ pub mod msg {
             real_roomid: u32,
             msg_common: String,
             msg_self: String,
+            /// 点击通知跳转的链接
+            link_url: String,
+            /// 通知类型，没有时为 0
+            notice_type: u32,
         },
         /// 类似于(就是) Youtube 的 SC
         SuperChatMessage {
@@ -331,6 +2803,16 @@ pub mod msg {
             price: u32,
             message: String,
             sender_name: String,
+            /// 人民币与金瓜子的汇率，没有时为 0
+            rate: u32,
+            /// 没有时为 0
+            gift_id: u32,
+            /// 是否有日文翻译
+            has_translation: bool,
+            /// 头像
+            face: String,
+            /// 用户等级
+            user_level: u32,
         },
         /// 直播对象为vtuber时会可以选择翻译为日文显示，货币单位并不会转换
         SuperChatMessageJapanese {
@@ -344,12 +2826,183 @@ pub mod msg {
             message_jpn: String,
             sender_name: String,
         },
+        /// 醒目留言入口配置
+        SuperChatEntrance {
+            broadcast_type: u32,
+            jump_url: String,
+        },
         /// 热门直播间通知
         HotRoomNotify,
+        /// 购物引导
+        GotoBuyFlow {
+            msg: String,
+        },
+        /// 热卖商品数量
+        HotBuyNum {
+            num: u32,
+            goods_id: String,
+        },
+        /// 推荐卡片
+        RecommendCard {
+            /// 推荐的直播间 ID
+            recommend_list: Vec<u32>,
+        },
+        /// 购物车展示
+        ShoppingCart {
+            status: u32,
+            goods_count: u32,
+        },
+        /// 登录提示
+        LoginNotice {
+            notice_msg: String,
+        },
+        /// 弹幕抽奖结果
+        DanmakuLotteryAward {
+            id: u64,
+            gift_name: String,
+            winners: Vec<LotWinner>,
+        },
+        /// 活动中奖
+        WinActivity {
+            uid: u32,
+            uname: String,
+            award_name: String,
+        },
+        /// 连麦铃声状态变更
+        RingStatusChange {
+            status: u32,
+        },
+        /// 虚拟主播礼物特效
+        VtrGift {
+            uid: u32,
+            uname: String,
+            gift_name: String,
+            action: String,
+        },
+        /// 开放平台抽奖
+        OpenPlatformLottery {
+            lot_id: String,
+            status: u32,
+        },
+        /// 多人同屏直播信息
+        MultiViewInfo {
+            /// 参与的直播间 ID
+            room_ids: Vec<u32>,
+        },
+        /// 千舰成就
+        GuardHonorThousand {
+            status: u32,
+            achieve: bool,
+        },
+        /// 活动横幅
+        ActivityBanner {
+            title: String,
+            cover: String,
+            timestamp: u32,
+            /// 活动结束时间
+            end_time: u32,
+        },
+        /// 新版红包
+        RedPocketNew {
+            lot_id: u64,
+            sender_uid: u32,
+            sender_name: String,
+            gift_name: String,
+            num: u32,
+            /// 剩余时间（秒）
+            remain_time: u32,
+        },
+        /// 互动聚合提示
+        DmInteraction {
+            /// 102: 弹幕连击，103: 关注，104: 分享
+            interaction_type: u32,
+            combo_count: u32,
+            text: String,
+        },
+        /// 人气榜引导卡
+        PopularRankGuideCard {
+            content: String,
+        },
+        /// 直播间模块布局变更
+        RoomModuleUpdate {
+            timestamp: u32,
+        },
+        /// 礼物星球任务完成奖励
+        GiftStarReward {
+            reward_name: String,
+            reward_num: u32,
+        },
+        /// 高能用户数
+        OnlineRankCount {
+            count: u32,
+            /// 在线人数，旧版消息没有
+            online_count: Option<u32>,
+        },
+        /// 荣耀等级升级
+        WealthNotify {
+            uid: u32,
+            level: u32,
+            score: u32,
+        },
+        /// 一起玩邀请
+        PlayTogether {
+            status: u32,
+            uid: u32,
+            uname: String,
+        },
+        /// 直播间背景图变更
+        ChangeRoomInfo {
+            background: String,
+            room_id: u32,
+        },
+        /// 上舰
+        GuardBuy {
+            /// 舰队等级
+            guard_level: GuardLevel,
+            uid: u32,
+            uname: String,
+            /// 购买数量
+            num: u32,
+            /// 购买单位，`月` 或 `年`
+            unit: String,
+            /// 总价（金瓜子）
+            price: u32,
+        },
+        /// 视频连线开始或结束
+        VideoConnection {
+            status: ConnectionStatus,
+            dmscore: u32,
+        },
+        /// 看过人数
+        WatchedChange {
+            num: u32,
+            text_large: String,
+            /// 简短显示的文字，没有时为空
+            text_small: String,
+        },
+        /// 直播间开启禁言，只允许舰队成员或粉丝勋章达到等级的用户发言
+        ChatRestriction {
+            guard_only: bool,
+            /// 发言所需的粉丝勋章等级，不限制时为 0
+            medal_level_required: u32,
+        },
+        /// 红包抽奖开始
+        RedPocketStart {
+            lot_id: u64,
+            sender_uid: u32,
+            sender_name: String,
+            /// 参与条件
+            require_type: RequireType,
+        },
         /// 未实现解析的消息
         Raw(json::JsonValue),
         /// 解析错误，指示可能的 API 变更
-        ParsingError(String),
+        ParsingError {
+            /// 消息的 cmd，json 无效时为 `None`
+            cmd: Option<String>,
+            /// 原始 json
+            raw: String,
+        },
     }
 
     impl Message {
@@ -370,15 +3023,50 @@ pub mod msg {
                 }
                 "DANMU_MSG" => {
                     let info = &mut json["info"];
+                    // `extra` is a JSON object encoded as a string
+                    let extra = json::parse(info[0][15]["extra"].as_str().unwrap_or_default())
+                        .unwrap_or(json::JsonValue::Null);
                     Danmaku {
                         mode: info[0][1].as_u32()?,
                         size: info[0][2].as_u32()?,
-                        color: info[0][3].as_u32()?,
+                        // Stickers are images, their color field is meaningless
+                        color: if info[0][12] == 1 {
+                            DEFAULT_DANMAKU_COLOR
+                        } else {
+                            info[0][3].as_u32()?
+                        },
                         dmid: info[0][5].as_i32()?,
                         text: info[1].take_string()?,
                         r#type: info[0][9].as_u32()?,
-                        uid: info[2][0].as_u32()?,
-                        uname: info[2][1].take_string()?,
+                        // Newer rooms send the user in info[0][15], older ones in info[2]
+                        uid: info[0][15]["user"]["uid"]
+                            .as_u32()
+                            .or_else(|| info[2][0].as_u32())?,
+                        uname: info[0][15]["user"]["base"]["name"]
+                            .as_str()
+                            .map(str::to_owned)
+                            .or_else(|| info[2][1].take_string())?,
+                        mentions: extra["reply_mid"]
+                            .as_u32()
+                            .filter(|&uid| uid != 0)
+                            .into_iter()
+                            .collect(),
+                        sent_at: info[0][4].as_u64().unwrap_or_default(),
+                        recognized_text: if info[0][14]["voice_url"].is_empty() {
+                            None
+                        } else {
+                            info[0][14]["text"].take_string()
+                        },
+                        user_level: info[4][0].as_u32(),
+                        guard_icon: info[0][15]["user"]["medal"]["guard_icon"]
+                            .take_string()
+                            .filter(|url| !url.is_empty()),
+                        is_lottery: info[0][9] == 2,
+                        is_checkin: extra["is_checkin"] == true,
+                        // -1 when the account isn't verified
+                        is_official: info[0][15]["user"]["base"]["official_info"]["type"]
+                            .as_i32()
+                            .is_some_and(|t| t >= 0),
                     }
                 }
                 "SEND_GIFT" => {
@@ -389,6 +3077,8 @@ pub mod msg {
                         num: data["num"].as_u32()?,
                         uid: data["uid"].as_u32()?,
                         uname: data["uname"].take_string()?,
+                        coin_type: data["coin_type"].take_string().unwrap_or_default(),
+                        total_coin: data["total_coin"].as_u32().unwrap_or_default(),
                     }
                 }
                 "COMBO_END" => {
@@ -437,6 +3127,7 @@ pub mod msg {
                         face: data["face"].take_string()?,
                         copy_writing: data["copy_writing"].take_string()?,
                         copy_color: data["copy_color"].take_string()?,
+                        wealth_level: data["wealthy_info"]["level"].as_u32().filter(|&n| n != 0),
                     }
                 }
                 "NOTICE_MSG" => NoticeMessage {
@@ -444,11 +3135,14 @@ pub mod msg {
                     real_roomid: json["real_roomid"].as_u32()?,
                     msg_common: json["msg_common"].take_string()?,
                     msg_self: json["msg_self"].take_string()?,
+                    link_url: json["link_url"].take_string().unwrap_or_default(),
+                    notice_type: json["msg_type"].as_u32().unwrap_or_default(),
                 },
                 "ROOM_REAL_TIME_MESSAGE_UPDATE" => {
                     let data = &mut json["data"];
                     RoomRealTimeMessageUpdate {
                         fans: data["fans"].as_u32()?,
+                        fans_club: data["fans_club"].as_u32().unwrap_or_default(),
                     }
                 }
                 "SUPER_CHAT_MESSAGE" => {
@@ -460,6 +3154,11 @@ pub mod msg {
                         price: data["price"].as_u32()?,
                         message: data["message"].take_string()?,
                         sender_name: data["user_info"]["uname"].take_string()?,
+                        rate: data["rate"].as_u32().unwrap_or_default(),
+                        gift_id: data["gift"]["gift_id"].as_u32().unwrap_or_default(),
+                        has_translation: data["trans_mark"] == 1,
+                        face: data["user_info"]["face"].take_string().unwrap_or_default(),
+                        user_level: data["user_info"]["user_level"].as_u32().unwrap_or_default(),
                     }
                 }
                 "SUPER_CHAT_MESSAGE_JPN" => {
@@ -473,10 +3172,525 @@ pub mod msg {
                         sender_name: data["user_info"]["uname"].take_string()?,
                     }
                 }
+                "SUPER_CHAT_ENTRANCE" => {
+                    let data = &mut json["data"];
+                    SuperChatEntrance {
+                        broadcast_type: data["broadcast_type"].as_u32()?,
+                        jump_url: data["jump_url"].take_string()?,
+                    }
+                }
                 "HOT_ROOM_NOTIFY" => HotRoomNotify,
+                "GOTO_BUY_FLOW" => parse_or_raw(json, |json| {
+                    Some(GotoBuyFlow {
+                        msg: json["data"]["text"].as_str()?.to_owned(),
+                    })
+                }),
+                "HOT_BUY_NUM" => {
+                    let data = &mut json["data"];
+                    HotBuyNum {
+                        num: data["num"].as_u32()?,
+                        goods_id: string_or_number(&data["goods_id"])?,
+                    }
+                }
+                "RECOMMEND_CARD" => parse_or_raw(json, |json| {
+                    let list = &json["data"]["recommend_list"];
+                    if !list.is_array() {
+                        return None;
+                    }
+                    Some(RecommendCard {
+                        recommend_list: list.members().map(|id| id.as_u32()).collect::<Option<_>>()?,
+                    })
+                }),
+                "SHOPPING_CART_SHOW" => {
+                    let data = &json["data"];
+                    ShoppingCart {
+                        status: data["status"].as_u32()?,
+                        goods_count: data["goods_count"].as_u32()?,
+                    }
+                }
+                "LOG_IN_NOTICE" => parse_or_raw(json, |json| {
+                    Some(LoginNotice {
+                        notice_msg: json["data"]["notice_msg"].as_str()?.to_owned(),
+                    })
+                }),
+                "DANMU_GIFT_LOTTERY_AWARD" => {
+                    let data = &mut json["data"];
+                    DanmakuLotteryAward {
+                        id: data["id"].as_u64()?,
+                        gift_name: data["gift_name"].take_string()?,
+                        winners: data["award_users"]
+                            .members_mut()
+                            .map(|user| {
+                                Some(LotWinner {
+                                    uid: user["uid"].as_u32()?,
+                                    uname: user["uname"].take_string()?,
+                                })
+                            })
+                            .collect::<Option<_>>()?,
+                    }
+                }
+                "WIN_ACTIVITY" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(WinActivity {
+                        uid: data["uid"].as_u32()?,
+                        uname: data["uname"].as_str()?.to_owned(),
+                        award_name: data["award_name"].as_str()?.to_owned(),
+                    })
+                }),
+                "RING_STATUS_CHANGE" => parse_or_raw(json, |json| {
+                    Some(RingStatusChange {
+                        status: json["data"]["status"].as_u32()?,
+                    })
+                }),
+                "VTR_GIFT" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(VtrGift {
+                        uid: data["uid"].as_u32()?,
+                        uname: data["uname"].as_str()?.to_owned(),
+                        gift_name: data["gift_name"].as_str()?.to_owned(),
+                        action: data["action"].as_str()?.to_owned(),
+                    })
+                }),
+                "LIVE_OPEN_PLATFORM_LOTTERY" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(OpenPlatformLottery {
+                        lot_id: string_or_number(&data["lot_id"])?,
+                        status: data["status"].as_u32()?,
+                    })
+                }),
+                "LIVE_MULTI_VIEW_INFO" => parse_or_raw(json, |json| {
+                    let list = &json["data"]["room_list"];
+                    if !list.is_array() {
+                        return None;
+                    }
+                    Some(MultiViewInfo {
+                        room_ids: list
+                            .members()
+                            .map(|room| room["room_id"].as_u32())
+                            .collect::<Option<_>>()?,
+                    })
+                }),
+                "GUARD_HONOR_THOUSAND" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    let achieve = &data["achieve"];
+                    Some(GuardHonorThousand {
+                        status: data["status"].as_u32()?,
+                        achieve: achieve.as_bool().or_else(|| achieve.as_u32().map(|n| n != 0))?,
+                    })
+                }),
+                "ACTIVITY_BANNER_UPDATE_V2" => {
+                    let data = &mut json["data"];
+                    ActivityBanner {
+                        title: data["title"].take_string()?,
+                        cover: data["cover"].take_string()?,
+                        timestamp: data["timestamp"].as_u32()?,
+                        end_time: data["end_time"].as_u32()?,
+                    }
+                }
+                "POPULARITY_RED_POCKET_NEW" => {
+                    let data = &mut json["data"];
+                    RedPocketNew {
+                        lot_id: data["lot_id"].as_u64()?,
+                        sender_uid: data["uid"].as_u32()?,
+                        sender_name: data["uname"].take_string()?,
+                        gift_name: data["gift_name"].take_string()?,
+                        num: data["num"].as_u32()?,
+                        remain_time: data["remain_time"].as_u32()?,
+                    }
+                }
+                "LIVE_OPEN_PLATFORM_DM" => {
+                    // Open platform danmaku have no display settings, use the defaults
+                    let data = &mut json["data"];
+                    Danmaku {
+                        mode: 1,
+                        size: 25,
+                        color: DEFAULT_DANMAKU_COLOR,
+                        dmid: 0,
+                        text: data["msg"].take_string()?,
+                        r#type: 0,
+                        uid: data["uid"].as_u32()?,
+                        uname: data["uname"].take_string()?,
+                        mentions: Vec::new(),
+                        sent_at: data["timestamp"].as_u64()?.checked_mul(1000)?,
+                        recognized_text: None,
+                        user_level: None,
+                        guard_icon: None,
+                        is_lottery: false,
+                        is_checkin: false,
+                        is_official: false,
+                    }
+                }
+                "DM_INTERACTION" => {
+                    let data = &json["data"];
+                    // The details are a json object encoded as a string
+                    let mut inner = json::parse(data["data"].as_str()?).ok()?;
+                    let (combo_count, text) = if inner["combo"].is_array() {
+                        let combo = &mut inner["combo"][0];
+                        (combo["cnt"].as_u32()?, combo["content"].take_string()?)
+                    } else {
+                        (inner["cnt"].as_u32()?, inner["suffix_text"].take_string()?)
+                    };
+                    DmInteraction {
+                        interaction_type: data["type"].as_u32()?,
+                        combo_count,
+                        text,
+                    }
+                }
+                "POPULAR_RANK_GUIDE_CARD" => parse_or_raw(json, |json| {
+                    Some(PopularRankGuideCard {
+                        content: json["data"]["content"].as_str()?.to_owned(),
+                    })
+                }),
+                "ROOM_MODULE_UPDATE" => parse_or_raw(json, |json| {
+                    Some(RoomModuleUpdate {
+                        timestamp: json["data"]["timestamp"].as_u32()?,
+                    })
+                }),
+                // Only the completion carries a reward, progress updates stay `Raw`
+                "GIFT_STAR_PROCESS" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(GiftStarReward {
+                        reward_name: data["reward_name"].as_str()?.to_owned(),
+                        reward_num: data["reward_num"].as_u32()?,
+                    })
+                }),
+                "ONLINE_RANK_COUNT" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(OnlineRankCount {
+                        count: data["count"].as_u32()?,
+                        online_count: data["online_count"].as_u32(),
+                    })
+                }),
+                "WEALTH_NOTIFY" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(WealthNotify {
+                        uid: data["uid"].as_u32()?,
+                        level: data["level"].as_u32()?,
+                        score: data["score"].as_u32()?,
+                    })
+                }),
+                "PLAY_TOGETHER" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(PlayTogether {
+                        status: data["status"].as_u32()?,
+                        uid: data["uid"].as_u32()?,
+                        uname: data["uname"].as_str()?.to_owned(),
+                    })
+                }),
+                "CHANGE_ROOM_INFO" => {
+                    let data = &mut json["data"];
+                    ChangeRoomInfo {
+                        background: data["background"].take_string()?,
+                        room_id: data["room_id"].as_u32()?,
+                    }
+                }
+                "USER_TOAST_MSG" => {
+                    let data = &mut json["data"];
+                    let guard_level = data["guard_level"].as_u32()?;
+                    GuardBuy {
+                        guard_level: GuardLevel::from(guard_level)?,
+                        uid: data["uid"].as_u32()?,
+                        uname: data["username"].take_string()?,
+                        num: data["num"].as_u32()?,
+                        unit: data["unit"].take_string()?,
+                        price: data["price"].as_u32()?,
+                    }
+                }
+                "VIDEO_CONNECTION_JOIN_START" => parse_or_raw(json, |json| {
+                    Some(VideoConnection {
+                        status: ConnectionStatus::Start,
+                        dmscore: json["dmscore"].as_u32()?,
+                    })
+                }),
+                "VIDEO_CONNECTION_JOIN_END" => parse_or_raw(json, |json| {
+                    Some(VideoConnection {
+                        status: ConnectionStatus::End,
+                        dmscore: json["dmscore"].as_u32()?,
+                    })
+                }),
+                "LIVE_OPEN_PLATFORM_SUPER_CHAT" => {
+                    // Open platform super chats aren't bought with a gift
+                    let data = &mut json["data"];
+                    SuperChatMessage {
+                        id: string_or_number(&data["message_id"])?,
+                        sender_uid: data["uid"].as_u32()?,
+                        price: data["rmb"].as_u32()?,
+                        message: data["message"].take_string()?,
+                        sender_name: data["uname"].take_string()?,
+                        rate: 1000,
+                        gift_id: 0,
+                        has_translation: false,
+                        face: data["uface"].take_string().unwrap_or_default(),
+                        user_level: 0,
+                    }
+                }
+                "WATCHED_CHANGE" => {
+                    let data = &mut json["data"];
+                    WatchedChange {
+                        num: data["num"].as_u32()?,
+                        text_large: data["text_large"].take_string()?,
+                        text_small: data["text_small"].take_string().unwrap_or_default(),
+                    }
+                }
+                "ROOM_SILENT_ON" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    let level = data["level"].as_u32()?;
+                    Some(match data["type"].as_str()? {
+                        "guard" => ChatRestriction {
+                            guard_only: true,
+                            medal_level_required: 0,
+                        },
+                        "medal" => ChatRestriction {
+                            guard_only: false,
+                            medal_level_required: level,
+                        },
+                        _ => return None,
+                    })
+                }),
+                "POPULARITY_RED_POCKET_START" => parse_or_raw(json, |json| {
+                    let data = &json["data"];
+                    Some(RedPocketStart {
+                        lot_id: data["lot_id"].as_u64()?,
+                        sender_uid: data["sender_uid"].as_u32()?,
+                        sender_name: data["sender_name"].as_str()?.to_owned(),
+                        require_type: RequireType::from(data["require_type"].as_u32()?)?,
+                    })
+                }),
                 _ => Raw(json),
             })
         }
+
+        /// Returns every `cmd` this version of the crate parses into a dedicated variant.
+        pub fn known_commands() -> &'static [&'static str] {
+            KNOWN_COMMANDS
+        }
+
+        /// Returns `true` if both messages are the same danmaku, ignoring
+        /// cosmetic fields such as color, size and mode.
+        ///
+        /// Danmaku are compared by `dmid`, or by sender and text if it's 0.
+        pub fn same_message(&self, other: &Message) -> bool {
+            match (self, other) {
+                (
+                    Danmaku {
+                        dmid, uid, text, ..
+                    },
+                    Danmaku {
+                        dmid: other_dmid,
+                        uid: other_uid,
+                        text: other_text,
+                        ..
+                    },
+                ) => {
+                    if *dmid != 0 || *other_dmid != 0 {
+                        dmid == other_dmid
+                    } else {
+                        uid == other_uid && text == other_text
+                    }
+                }
+                _ => false,
+            }
+        }
+
+        /// Returns the unparsable json string if the message is a `ParsingError`.
+        pub fn as_parsing_error(&self) -> Option<&str> {
+            match self {
+                ParsingError { raw, .. } => Some(raw),
+                _ => None,
+            }
+        }
+
+        /// Returns the json if the message is `Raw`.
+        pub fn as_raw(&self) -> Option<&json::JsonValue> {
+            match self {
+                Raw(json) => Some(json),
+                _ => None,
+            }
+        }
+
+        /// Returns `true` if the message represents income of the streamer.
+        pub fn is_revenue_event(&self) -> bool {
+            self.revenue_value().is_some()
+        }
+
+        /// Returns the monetary value of the message in gold coins (金瓜子),
+        /// where 1000 gold coins equal 1 CNY.
+        ///
+        /// Free (silver) gifts and messages without value return `None`.
+        pub fn revenue_value(&self) -> Option<u32> {
+            match self {
+                SendGift {
+                    coin_type,
+                    total_coin,
+                    ..
+                } if coin_type == "gold" => Some(*total_coin),
+                GuardBuy { price, .. } => Some(*price),
+                SuperChatMessage { price, .. } | SuperChatMessageJapanese { price, .. } => {
+                    Some(price * 1000)
+                }
+                _ => None,
+            }
+        }
+
+        /// Returns when the message was sent, for messages carrying a timestamp.
+        pub fn timestamp(&self) -> Option<SystemTime> {
+            match self {
+                // The only timestamp in milliseconds
+                Danmaku { sent_at, .. } => Some(UNIX_EPOCH + Duration::from_millis(*sent_at)),
+                RoomRank { timestamp, .. }
+                | ActivityBanner { timestamp, .. }
+                | RoomModuleUpdate { timestamp, .. } => {
+                    Some(UNIX_EPOCH + Duration::from_secs(u64::from(*timestamp)))
+                }
+                _ => None,
+            }
+        }
+
+        /// Returns `timestamp` as a UTC `DateTime`.
+        #[cfg(feature = "chrono")]
+        pub fn datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+            self.timestamp().map(Into::into)
+        }
+
+        /// Returns the common fields of `SuperChatMessage` and
+        /// `SuperChatMessageJapanese`, so both can be handled at once.
+        pub fn super_chat(&self) -> Option<SuperChatView<'_>> {
+            match self {
+                SuperChatMessage {
+                    id,
+                    price,
+                    message,
+                    sender_name,
+                    ..
+                } => Some(SuperChatView {
+                    id,
+                    price: *price,
+                    message,
+                    message_jpn: None,
+                    sender_name,
+                }),
+                SuperChatMessageJapanese {
+                    id,
+                    price,
+                    message,
+                    message_jpn,
+                    sender_name,
+                    ..
+                } => Some(SuperChatView {
+                    id,
+                    price: *price,
+                    message,
+                    message_jpn: Some(message_jpn),
+                    sender_name,
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    #[cfg(feature = "test-util")]
+    impl Message {
+        /// Creates a plain white danmaku, for tests and simulations.
+        pub fn danmaku(uid: u32, uname: &str, text: &str) -> Message {
+            Danmaku {
+                mode: 1,
+                size: 25,
+                color: DEFAULT_DANMAKU_COLOR,
+                dmid: 0,
+                text: text.to_owned(),
+                r#type: 0,
+                uid,
+                uname: uname.to_owned(),
+                mentions: Vec::new(),
+                sent_at: 0,
+                recognized_text: None,
+                user_level: None,
+                guard_icon: None,
+                is_lottery: false,
+                is_checkin: false,
+                is_official: false,
+            }
+        }
+
+        /// Creates a paid gift, for tests and simulations.
+        pub fn send_gift(
+            uid: u32,
+            uname: &str,
+            gift_name: &str,
+            num: u32,
+            total_coin: u32,
+        ) -> Message {
+            SendGift {
+                action: "投喂".to_owned(),
+                gift_name: gift_name.to_owned(),
+                num,
+                uid,
+                uname: uname.to_owned(),
+                coin_type: "gold".to_owned(),
+                total_coin,
+            }
+        }
+    }
+
+    /// Parses `json` with `f`, falling back to `Raw` if any required field is null.
+    fn parse_or_raw(
+        json: json::JsonValue,
+        f: impl FnOnce(&json::JsonValue) -> Option<Message>,
+    ) -> Message {
+        f(&json).unwrap_or(Raw(json))
+    }
+
+    /// Returns the string in `v`, accepting ids sent as numbers as well.
+    fn string_or_number(v: &json::JsonValue) -> Option<String> {
+        if v.is_number() {
+            Some(v.dump())
+        } else {
+            v.as_str().map(str::to_owned)
+        }
+    }
+
+    /// 醒目留言，见 `Message::super_chat`
+    pub struct SuperChatView<'a> {
+        pub id: &'a str,
+        /// 打赏金额（元）
+        pub price: u32,
+        pub message: &'a str,
+        /// 翻译之后的日文
+        pub message_jpn: Option<&'a str>,
+        pub sender_name: &'a str,
+    }
+
+    /// 视频连线状态
+    pub enum ConnectionStatus {
+        Start,
+        End,
+    }
+
+    /// 红包抽奖的参与条件
+    pub enum RequireType {
+        /// 无条件
+        None,
+        /// 关注主播
+        FollowRoom,
+        /// 发送弹幕
+        SendDanmaku,
+    }
+
+    impl RequireType {
+        fn from(n: u32) -> Option<Self> {
+            Some(match n {
+                0 => RequireType::None,
+                1 => RequireType::FollowRoom,
+                2 => RequireType::SendDanmaku,
+                _ => return None,
+            })
+        }
+    }
+
+    /// 抽奖中奖者
+    pub struct LotWinner {
+        pub uid: u32,
+        pub uname: String,
     }
 
     pub enum GuardLevel {
@@ -512,4 +3726,1064 @@ pub mod msg {
             })
         }
     }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+
+        /// A danmaku in the layout with the user in `info[0][15]`
+        pub(crate) const DANMU_MSG: &str = r##"{"cmd":"DANMU_MSG","dm_v2":"","info":[[0,1,25,16777215,1697012345678,1697012345,0,"5c7f1a2b",0,0,0,"",0,"{}","{}",{"mode":0,"show_player_type":0,"extra":"{\"send_from_me\":false,\"mode\":0,\"color\":16777215,\"dm_type\":0,\"font_size\":25,\"player_mode\":1,\"show_player_type\":0,\"content\":\"晚上好\",\"user_hash\":\"1543234234\",\"emoticon_unique\":\"\",\"bulge_display\":0,\"recommend_score\":1,\"direction\":0,\"pk_direction\":0,\"reply_mid\":0,\"reply_uname\":\"\",\"hit_combo\":0}","user":{"uid":4204531,"base":{"name":"路过的观众","face":"https://i0.hdslb.com/bfs/face/member/noface.jpg","name_color":0,"is_mystery":false,"official_info":{"role":0,"title":"","desc":"","type":-1}},"medal":{"name":"小电视","level":12,"color_start":6067854,"color_end":6067854,"color_border":6067854,"color":6067854,"id":0,"typ":0,"is_light":1,"ruid":7706705,"guard_level":0,"score":50012345,"guard_icon":"","honor_icon":"","v2_medal_color_start":"#5E6B8E99","user_receive_count":0},"wealth":{"level":14,"dm_icon_key":""},"title":{"old_title_css_id":"","title_css_id":""},"guard":null,"uhead_frame":null,"guard_leader":{"is_guard_leader":false}}},{"activity_identity":"","activity_source":0,"not_show":0},0],"晚上好",[4204531,"路过的观众",0,0,0,10000,1,""],[12,"小电视","哔哩哔哩直播",7706705,6067854,"",0,6067854,6067854,6067854,0,1,7706705],[21,0,5805790,">50000",0],["",""],0,0,null,{"ts":1697012345,"ct":"8A1D2C3E"},0,0,null,null,0,105,[14],null]}"##;
+
+        /// A super chat with a Japanese translation
+        pub(crate) const SUPER_CHAT_MESSAGE: &str = r##"{"cmd":"SUPER_CHAT_MESSAGE","data":{"background_bottom_color":"#2A60B2","background_color":"#EDF5FF","background_image":"https://i0.hdslb.com/bfs/live/a712efa5c6ebc67bafbe8352d3e74b820a00c13e.png","background_price_color":"#7497CD","color_point":0.7,"dmscore":112,"end_time":1697012405,"gift":{"gift_id":12000,"gift_name":"醒目留言","num":1},"id":"8215836","is_ranked":1,"is_send_audit":1,"medal_info":{"anchor_roomid":21452505,"anchor_uname":"主播","guard_level":3,"icon_id":0,"is_lighted":1,"medal_color":"#6154c","medal_level":21,"medal_name":"小电视","target_id":7706705},"message":"主播晚上好","message_font_color":"#A3F6FF","message_trans":"配信者さん、こんばんは","price":30,"rate":1000,"start_time":1697012345,"time":60,"token":"AB12CD34","trans_mark":1,"ts":1697012345,"uid":4204531,"user_info":{"face":"https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg","face_frame":"https://i0.hdslb.com/bfs/live/80f732943cc3367029df65e267960d56736a82ee.png","guard_level":3,"is_main_vip":1,"is_svip":0,"is_vip":0,"level_color":"#61c05a","manager":0,"name_color":"#00D1F1","title":"0","uname":"路过的观众","user_level":23}},"roomid":21452505}"##;
+
+        /// A paid gift
+        pub(crate) const SEND_GIFT: &str = r#"{"cmd":"SEND_GIFT","data":{"action":"投喂","batch_combo_id":"batch:gift:combo_id:4204531:7706705:31036:1697012345.1234","beatId":"0","biz_source":"Live","coin_type":"gold","combo_send":null,"combo_stay_time":5,"combo_total_coin":1000,"discount_price":100,"dmscore":56,"draw":0,"effect":0,"face":"https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg","giftId":31036,"giftName":"小花花","giftType":0,"gold":0,"guard_level":0,"is_first":false,"magnification":1,"medal_info":{"anchor_roomid":0,"anchor_uname":"","guard_level":0,"icon_id":0,"is_lighted":1,"medal_level":12,"medal_name":"小电视","target_id":7706705},"num":10,"price":100,"rcost":200769,"remain":0,"rnd":"1697012340123456789","silver":0,"super":0,"super_batch_gift_num":10,"super_gift_num":10,"tid":"1697012345120200001","timestamp":1697012345,"total_coin":1000,"uid":4204531,"uname":"路过的观众"}}"#;
+
+        /// A danmaku in the older layout with the user in `info[2]`
+        const DANMU_MSG_LEGACY: &str = r#"{"cmd":"DANMU_MSG","info":[[0,1,25,14893055,1598371234567,1598371234,0,"8f2e3a1c",0,0,0,"",0,"{}","{}"],"草",[1864273,"老观众",0,0,0,10000,1,""],[],[9,0,9868950,">50000"],["",""],0,0,null,{"ts":1598371234,"ct":"E4B2A0F1"},0,0,null,null,0]}"#;
+
+        fn parse(json: &str) -> Message {
+            Message::parse(json::parse(json).unwrap()).unwrap()
+        }
+
+        /// Parses `DANMU_MSG` after applying `f` to its json and the json in its
+        /// `extra` string.
+        fn danmaku(f: impl FnOnce(&mut json::JsonValue, &mut json::JsonValue)) -> Message {
+            let mut json = json::parse(DANMU_MSG).unwrap();
+            let mut extra = json::parse(json["info"][0][15]["extra"].as_str().unwrap()).unwrap();
+            f(&mut json, &mut extra);
+            json["info"][0][15]["extra"] = extra.dump().into();
+            Message::parse(json).unwrap()
+        }
+
+        #[test]
+        fn parses_danmaku_sent_at() {
+            match parse(DANMU_MSG) {
+                Danmaku { sent_at, .. } => assert_eq!(sent_at, 1697012345678),
+                _ => panic!("expected a danmaku"),
+            }
+            // Missing in some relayed danmaku, which are still kept
+            match danmaku(|json, _| json["info"][0][4] = json::Null) {
+                Danmaku { sent_at, .. } => assert_eq!(sent_at, 0),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        /// Returns the commands matched at the top level of `Message::parse`.
+        fn parse_arms() -> Vec<&'static str> {
+            let src = include_str!("lib.rs");
+            let start = src.find("pub fn parse(mut json").unwrap();
+            let end = start + src[start..].find("_ => Raw(json),").unwrap();
+            src[start..end]
+                .lines()
+                // Nested matches are indented further
+                .filter_map(|line| line.strip_prefix(&" ".repeat(16)))
+                .filter(|line| line.starts_with('"'))
+                .flat_map(|line| {
+                    let pattern = &line[..line.find(" =>").unwrap()];
+                    pattern.split(" | ").map(|cmd| cmd.trim_matches('"'))
+                })
+                .collect()
+        }
+
+        #[test]
+        fn known_commands_match_parse_arms() {
+            let arms = parse_arms();
+            for cmd in &arms {
+                assert!(KNOWN_COMMANDS.contains(cmd), "{} is missing from KNOWN_COMMANDS", cmd);
+            }
+            for cmd in KNOWN_COMMANDS {
+                assert!(arms.contains(cmd), "{} has no arm in Message::parse", cmd);
+            }
+            assert_eq!(arms.len(), KNOWN_COMMANDS.len());
+        }
+
+        #[test]
+        fn unknown_commands_are_raw() {
+            let json = json::object! { "cmd" => "NOT_A_COMMAND" };
+            assert!(matches!(Message::parse(json), Some(Raw(_))));
+            assert!(!KNOWN_COMMANDS.contains(&"NOT_A_COMMAND"));
+        }
+
+        #[test]
+        fn parses_notice_link_and_type() {
+            let json = r#"{"cmd":"NOTICE_MSG","id":2,"name":"分区道具抽奖","full":{"head_icon":"","tail_icon":""},"half":{"head_icon":"","tail_icon":""},"side":{"head_icon":"","background":""},"roomid":22637261,"real_roomid":22637261,"msg_common":"<%某用户%>投喂:<%主播%>1个摩天大楼，点击前往TA的房间吧！","msg_self":"<%某用户%>投喂:<%主播%>1个摩天大楼，快来围观吧！","link_url":"https://live.bilibili.com/22637261?from=28003&extra_jump_from=28003","msg_type":2,"shield_uid":-1,"business_id":"20003","scatter":{"min":0,"max":0},"marquee_id":"","notice_type":0}"#;
+            match parse(json) {
+                NoticeMessage {
+                    roomid,
+                    link_url,
+                    notice_type,
+                    ..
+                } => {
+                    assert_eq!(roomid, 22637261);
+                    assert_eq!(
+                        link_url,
+                        "https://live.bilibili.com/22637261?from=28003&extra_jump_from=28003"
+                    );
+                    assert_eq!(notice_type, 2);
+                }
+                _ => panic!("expected a notice"),
+            }
+
+            let json = r#"{"cmd":"NOTICE_MSG","roomid":1,"real_roomid":1,"msg_common":"","msg_self":""}"#;
+            match parse(json) {
+                NoticeMessage {
+                    link_url,
+                    notice_type,
+                    ..
+                } => assert!(link_url.is_empty() && notice_type == 0),
+                _ => panic!("expected a notice"),
+            }
+        }
+
+
+        #[test]
+        fn parses_super_chat_rate_and_gift() {
+            match parse(SUPER_CHAT_MESSAGE) {
+                SuperChatMessage {
+                    id,
+                    price,
+                    rate,
+                    gift_id,
+                    has_translation,
+                    ..
+                } => {
+                    assert_eq!(id, "8215836");
+                    assert_eq!(price, 30);
+                    assert_eq!(rate, 1000);
+                    assert_eq!(gift_id, 12000);
+                    assert!(has_translation);
+                }
+                _ => panic!("expected a super chat"),
+            }
+
+            let mut json = json::parse(SUPER_CHAT_MESSAGE).unwrap();
+            json["data"].remove("rate");
+            json["data"].remove("gift");
+            json["data"]["trans_mark"] = 0.into();
+            match Message::parse(json).unwrap() {
+                SuperChatMessage {
+                    rate,
+                    gift_id,
+                    has_translation,
+                    ..
+                } => assert!(rate == 0 && gift_id == 0 && !has_translation),
+                _ => panic!("expected a super chat"),
+            }
+        }
+
+        #[test]
+        fn values_revenue_events() {
+            let gift = parse(SEND_GIFT);
+            assert!(gift.is_revenue_event());
+            assert_eq!(gift.revenue_value(), Some(1000));
+
+            let mut json = json::parse(SEND_GIFT).unwrap();
+            json["data"]["giftName"] = "辣条".into();
+            json["data"]["coin_type"] = "silver".into();
+            let silver_gift = Message::parse(json).unwrap();
+            assert!(!silver_gift.is_revenue_event());
+            assert_eq!(silver_gift.revenue_value(), None);
+
+            let super_chat = parse(SUPER_CHAT_MESSAGE);
+            assert!(super_chat.is_revenue_event());
+            assert_eq!(super_chat.revenue_value(), Some(30_000));
+
+            let danmaku = parse(DANMU_MSG);
+            assert!(!danmaku.is_revenue_event());
+            assert_eq!(danmaku.revenue_value(), None);
+        }
+
+        #[test]
+        fn keeps_gifts_without_coin_fields() {
+            let mut json = json::parse(SEND_GIFT).unwrap();
+            json["data"].remove("coin_type");
+            json["data"].remove("total_coin");
+            match Message::parse(json).unwrap() {
+                ref gift @ SendGift { ref gift_name, .. } => {
+                    assert_eq!(gift_name, "小花花");
+                    assert!(!gift.is_revenue_event());
+                }
+                _ => panic!("expected a gift"),
+            }
+        }
+
+        #[test]
+        fn parses_fans_and_fans_club() {
+            let json = r#"{"cmd":"ROOM_REAL_TIME_MESSAGE_UPDATE","data":{"roomid":21452505,"fans":1093648,"red_notice":-1,"fans_club":35312}}"#;
+            match parse(json) {
+                RoomRealTimeMessageUpdate { fans, fans_club } => {
+                    assert_eq!(fans, 1093648);
+                    assert_eq!(fans_club, 35312);
+                }
+                _ => panic!("expected a fans update"),
+            }
+
+            let json = r#"{"cmd":"ROOM_REAL_TIME_MESSAGE_UPDATE","data":{"roomid":21452505,"fans":1093648}}"#;
+            match parse(json) {
+                RoomRealTimeMessageUpdate { fans, fans_club } => {
+                    assert_eq!(fans, 1093648);
+                    assert_eq!(fans_club, 0);
+                }
+                _ => panic!("expected a fans update"),
+            }
+        }
+
+        #[test]
+        fn parses_open_platform_danmaku() {
+            let json = r#"{"cmd":"LIVE_OPEN_PLATFORM_DM","data":{"room_id":21452505,"uid":4204531,"open_id":"39b8fedb-60a5-4e29-ac75-b16955f7e632","uname":"路过的观众","msg":"开放平台弹幕","msg_id":"1b4a5e62-9d1a-4c4e-9a3e-0c1f2d3e4f50","fans_medal_level":12,"fans_medal_name":"小电视","fans_medal_wearing_status":true,"guard_level":0,"timestamp":1697012345,"uface":"https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg","emoji_img_url":"","dm_type":0,"glory_level":14,"reply_open_id":"","reply_uname":"","is_admin":0}}"#;
+            match parse(json) {
+                Danmaku {
+                    text,
+                    uid,
+                    uname,
+                    sent_at,
+                    ..
+                } => {
+                    assert_eq!(text, "开放平台弹幕");
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                    assert_eq!(sent_at, 1697012345000);
+                }
+                _ => panic!("expected a danmaku"),
+            }
+
+            // A timestamp that overflows in milliseconds fails the parse
+            let mut json = json::parse(json).unwrap();
+            json["data"]["timestamp"] = u64::MAX.into();
+            assert!(Message::parse(json).is_none());
+        }
+
+        #[test]
+        fn exposes_raw_and_unparsable_json() {
+            let error = ParsingError {
+                cmd: Some("DANMU_MSG".to_owned()),
+                raw: r#"{"cmd":"DANMU_MSG"}"#.to_owned(),
+            };
+            assert_eq!(error.as_parsing_error(), Some(r#"{"cmd":"DANMU_MSG"}"#));
+            assert!(error.as_raw().is_none());
+
+            let raw = parse(r#"{"cmd":"SOME_NEW_COMMAND","data":{"a":1}}"#);
+            assert_eq!(raw.as_raw().unwrap()["data"]["a"], 1);
+            assert!(raw.as_parsing_error().is_none());
+
+            let danmaku = parse(DANMU_MSG);
+            assert!(danmaku.as_raw().is_none());
+            assert!(danmaku.as_parsing_error().is_none());
+        }
+
+        #[test]
+        fn compares_danmaku_ignoring_cosmetics() {
+            let original = parse(DANMU_MSG);
+            let restyled = danmaku(|json, _| {
+                json["info"][0][1] = 4.into();
+                json["info"][0][2] = 18.into();
+                json["info"][0][3] = 0xe33fff.into();
+            });
+            assert!(original.same_message(&restyled));
+            let other = danmaku(|json, _| json["info"][0][5] = 42.into());
+            assert!(!original.same_message(&other));
+
+            // Without dmid, the sender and text are compared
+            let without_dmid = |text: &'static str, uid: u32| {
+                danmaku(move |json, _| {
+                    json["info"][0][5] = 0.into();
+                    json["info"][0][3] = 0xe33fff.into();
+                    json["info"][1] = text.into();
+                    json["info"][0][15]["user"]["uid"] = uid.into();
+                })
+            };
+            let resent = without_dmid("晚上好", 4204531);
+            assert!(resent.same_message(&without_dmid("晚上好", 4204531)));
+            assert!(!resent.same_message(&without_dmid("早上好", 4204531)));
+            assert!(!resent.same_message(&without_dmid("晚上好", 1)));
+
+            assert!(!original.same_message(&parse(SEND_GIFT)));
+        }
+
+        #[cfg(feature = "test-util")]
+        #[test]
+        fn builds_test_messages() {
+            match Message::danmaku(4204531, "路过的观众", "晚上好") {
+                Danmaku {
+                    uid,
+                    uname,
+                    text,
+                    color,
+                    ..
+                } => {
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                    assert_eq!(text, "晚上好");
+                    assert_eq!(color, DEFAULT_DANMAKU_COLOR);
+                }
+                _ => panic!("expected a danmaku"),
+            }
+
+            let gift = Message::send_gift(4204531, "路过的观众", "小花花", 10, 1000);
+            match &gift {
+                SendGift {
+                    uid,
+                    gift_name,
+                    num,
+                    ..
+                } => assert!(*uid == 4204531 && gift_name == "小花花" && *num == 10),
+                _ => panic!("expected a gift"),
+            }
+            assert_eq!(gift.revenue_value(), Some(1000));
+        }
+
+        #[test]
+        fn views_both_super_chat_variants() {
+            let view = parse(SUPER_CHAT_MESSAGE);
+            let view = view.super_chat().unwrap();
+            assert_eq!(view.id, "8215836");
+            assert_eq!(view.price, 30);
+            assert_eq!(view.message, "主播晚上好");
+            assert_eq!(view.message_jpn, None);
+            assert_eq!(view.sender_name, "路过的观众");
+
+            let json = r#"{"cmd":"SUPER_CHAT_MESSAGE_JPN","data":{"id":"8215836","uid":"4204531","price":30,"rate":1000,"message":"主播晚上好","message_jpn":"配信者さん、こんばんは","is_ranked":1,"background_image":"","ts":1697012345,"start_time":1697012345,"end_time":1697012405,"time":60,"token":"AB12CD34","user_info":{"uname":"路过的观众","face":"https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg","guard_level":3,"user_level":23}},"roomid":"21452505"}"#;
+            let view = parse(json);
+            let view = view.super_chat().unwrap();
+            assert_eq!(view.id, "8215836");
+            assert_eq!(view.price, 30);
+            assert_eq!(view.message, "主播晚上好");
+            assert_eq!(view.message_jpn, Some("配信者さん、こんばんは"));
+            assert_eq!(view.sender_name, "路过的观众");
+
+            assert!(parse(DANMU_MSG).super_chat().is_none());
+        }
+
+        #[test]
+        fn converts_timestamps() {
+            // In milliseconds
+            let danmaku = parse(DANMU_MSG);
+            let expected = UNIX_EPOCH + Duration::from_millis(1697012345678);
+            assert_eq!(danmaku.timestamp(), Some(expected));
+
+            // In seconds
+            let json = r##"{"cmd":"ROOM_RANK","data":{"roomid":21452505,"rank_desc":"娱乐小时榜 7","color":"#FB7299","h5_url":"","web_url":"","timestamp":1697012345}}"##;
+            let rank = parse(json);
+            assert_eq!(rank.timestamp(), Some(UNIX_EPOCH + Duration::from_secs(1697012345)));
+
+            assert_eq!(parse(SEND_GIFT).timestamp(), None);
+        }
+
+        #[cfg(feature = "chrono")]
+        #[test]
+        fn converts_timestamps_to_datetimes() {
+            let datetime = parse(DANMU_MSG).datetime().unwrap();
+            assert_eq!(datetime.timestamp_millis(), 1697012345678);
+            assert_eq!(datetime.to_rfc3339(), "2023-10-11T08:19:05.678+00:00");
+            assert_eq!(parse(SEND_GIFT).datetime(), None);
+        }
+
+        #[test]
+        fn parses_super_chat_entrance() {
+            let json = r#"{"cmd":"SUPER_CHAT_ENTRANCE","data":{"broadcast_type":0,"icon":"https://i0.hdslb.com/bfs/live/0a9ebd72c76e9cbede9547386dd453475d4af6fe.png","jump_url":"https://live.bilibili.com/p/html/live-app-superchat2/index.html?is_live_half_webview=1&hybrid_half_ui=1,3,100p,70p,ffffff,0,30,100;2,2,375,100p,ffffff,0,30,100,0,0&anchor_uid=7706705&room_id=21452505","status":1},"roomid":21452505}"#;
+            match parse(json) {
+                SuperChatEntrance {
+                    broadcast_type,
+                    jump_url,
+                } => {
+                    assert_eq!(broadcast_type, 0);
+                    assert!(jump_url.starts_with("https://live.bilibili.com/p/html/live-app-superchat2/"));
+                    assert!(jump_url.ends_with("&room_id=21452505"));
+                }
+                _ => panic!("expected a super chat entrance"),
+            }
+        }
+
+        #[test]
+        fn parses_mentions() {
+            match parse(DANMU_MSG) {
+                Danmaku { mentions, .. } => assert!(mentions.is_empty()),
+                _ => panic!("expected a danmaku"),
+            }
+
+            let mention = danmaku(|json, extra| {
+                json["info"][1] = "@主播 晚上好".into();
+                extra["reply_mid"] = 7706705.into();
+                extra["reply_uname"] = "主播".into();
+            });
+            match mention {
+                Danmaku { mentions, .. } => assert_eq!(mentions, [7706705]),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_goto_buy_flow() {
+            let json = r#"{"cmd":"GOTO_BUY_FLOW","data":{"text":"路过的观众 正在去买"}}"#;
+            match parse(json) {
+                GotoBuyFlow { msg } => assert_eq!(msg, "路过的观众 正在去买"),
+                _ => panic!("expected a buy flow"),
+            }
+            assert!(parse(r#"{"cmd":"GOTO_BUY_FLOW","data":{}}"#).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_hot_buy_num() {
+            let number = r#"{"cmd":"HOT_BUY_NUM","data":{"goods_id":1123,"num":26}}"#;
+            let string = r#"{"cmd":"HOT_BUY_NUM","data":{"goods_id":"1123","num":26}}"#;
+            for json in &[number, string] {
+                match parse(json) {
+                    HotBuyNum { num, goods_id } => {
+                        assert_eq!(num, 26);
+                        assert_eq!(goods_id, "1123");
+                    }
+                    _ => panic!("expected a hot buy count"),
+                }
+            }
+        }
+
+        #[test]
+        fn parses_recommend_card() {
+            let json = r#"{"cmd":"RECOMMEND_CARD","data":{"recommend_list":[21452505,7734200],"timestamp":1697012345}}"#;
+            match parse(json) {
+                RecommendCard { recommend_list } => assert_eq!(recommend_list, [21452505, 7734200]),
+                _ => panic!("expected a recommend card"),
+            }
+            let json = r#"{"cmd":"RECOMMEND_CARD","data":{"recommend_list":[{"room_id":21452505}]}}"#;
+            assert!(parse(json).as_raw().is_some());
+            assert!(parse(r#"{"cmd":"RECOMMEND_CARD","data":{}}"#).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_shopping_cart_show() {
+            let json = r#"{"cmd":"SHOPPING_CART_SHOW","data":{"status":1,"goods_count":12}}"#;
+            match parse(json) {
+                ShoppingCart {
+                    status,
+                    goods_count,
+                } => assert!(status == 1 && goods_count == 12),
+                _ => panic!("expected a shopping cart"),
+            }
+        }
+
+        #[test]
+        fn parses_log_in_notice() {
+            let json = r#"{"cmd":"LOG_IN_NOTICE","data":{"notice_msg":"为保护用户隐私，未登录无法查看他人昵称","image_web":"","image_app":""}}"#;
+            match parse(json) {
+                LoginNotice { notice_msg } => {
+                    assert_eq!(notice_msg, "为保护用户隐私，未登录无法查看他人昵称");
+                }
+                _ => panic!("expected a login notice"),
+            }
+            assert!(parse(r#"{"cmd":"LOG_IN_NOTICE","data":{}}"#).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_danmaku_lottery_awards() {
+            let json = r#"{"cmd":"DANMU_GIFT_LOTTERY_AWARD","data":{"id":5233701,"gift_name":"小花花","award_users":[{"uid":4204531,"uname":"路过的观众"},{"uid":1234567,"uname":"另一位观众"}]}}"#;
+            match parse(json) {
+                DanmakuLotteryAward {
+                    id,
+                    gift_name,
+                    winners,
+                } => {
+                    assert_eq!(id, 5233701);
+                    assert_eq!(gift_name, "小花花");
+                    assert_eq!(winners.len(), 2);
+                    assert_eq!(winners[0].uid, 4204531);
+                    assert_eq!(winners[0].uname, "路过的观众");
+                    assert_eq!(winners[1].uid, 1234567);
+                    assert_eq!(winners[1].uname, "另一位观众");
+                }
+                _ => panic!("expected a lottery award"),
+            }
+        }
+
+        #[test]
+        fn parses_win_activity() {
+            let json = r#"{"cmd":"WIN_ACTIVITY","data":{"uid":4204531,"uname":"路过的观众","award_name":"小电视抱枕","number":1}}"#;
+            match parse(json) {
+                WinActivity {
+                    uid,
+                    uname,
+                    award_name,
+                } => {
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                    assert_eq!(award_name, "小电视抱枕");
+                }
+                _ => panic!("expected an activity win"),
+            }
+            let json = r#"{"cmd":"WIN_ACTIVITY","data":{"uid":4204531,"uname":"路过的观众"}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_ring_status_change() {
+            let json = r#"{"cmd":"RING_STATUS_CHANGE","data":{"status":2}}"#;
+            match parse(json) {
+                RingStatusChange { status } => assert_eq!(status, 2),
+                _ => panic!("expected a ring status"),
+            }
+            assert!(parse(r#"{"cmd":"RING_STATUS_CHANGE","data":{}}"#).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_vtr_gift() {
+            let json = r#"{"cmd":"VTR_GIFT","data":{"uid":4204531,"uname":"路过的观众","gift_name":"星愿水晶球","action":"投喂","num":1}}"#;
+            match parse(json) {
+                VtrGift {
+                    uid,
+                    uname,
+                    gift_name,
+                    action,
+                } => {
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                    assert_eq!(gift_name, "星愿水晶球");
+                    assert_eq!(action, "投喂");
+                }
+                _ => panic!("expected a VTR gift"),
+            }
+        }
+
+        #[test]
+        fn parses_voice_danmaku_text() {
+            let voice = danmaku(|json, _| {
+                json["info"][0][14] = json::object! {
+                    "voice_url" => "https://boss.hdslb.com/live-dm-voice/voice.wav",
+                    "file_format" => "wav",
+                    "text" => "主播晚上好",
+                    "file_duration" => 3
+                };
+            });
+            match voice {
+                Danmaku {
+                    recognized_text, ..
+                } => assert_eq!(recognized_text.as_deref(), Some("主播晚上好")),
+                _ => panic!("expected a danmaku"),
+            }
+            match parse(DANMU_MSG) {
+                Danmaku {
+                    recognized_text, ..
+                } => assert_eq!(recognized_text, None),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_danmaku_user_level() {
+            match parse(DANMU_MSG) {
+                Danmaku { user_level, .. } => assert_eq!(user_level, Some(21)),
+                _ => panic!("expected a danmaku"),
+            }
+            match danmaku(|json, _| json["info"][4] = json::Null) {
+                Danmaku { user_level, .. } => assert_eq!(user_level, None),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_open_platform_lottery() {
+            let json = r#"{"cmd":"LIVE_OPEN_PLATFORM_LOTTERY","data":{"lot_id":"9800123","status":1,"room_id":21452505}}"#;
+            match parse(json) {
+                OpenPlatformLottery { lot_id, status } => {
+                    assert_eq!(lot_id, "9800123");
+                    assert_eq!(status, 1);
+                }
+                _ => panic!("expected an open platform lottery"),
+            }
+            let json = r#"{"cmd":"LIVE_OPEN_PLATFORM_LOTTERY","data":{"lot_id":"9800123"}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_multi_view_info() {
+            let json = r#"{"cmd":"LIVE_MULTI_VIEW_INFO","data":{"room_list":[{"room_id":21452505,"title":"主播"},{"room_id":7734200,"title":"嘉宾"}]}}"#;
+            match parse(json) {
+                MultiViewInfo { room_ids } => assert_eq!(room_ids, [21452505, 7734200]),
+                _ => panic!("expected multi-view info"),
+            }
+            assert!(parse(r#"{"cmd":"LIVE_MULTI_VIEW_INFO","data":{}}"#).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_guard_honor_thousand() {
+            let flag = r#"{"cmd":"GUARD_HONOR_THOUSAND","data":{"status":1,"achieve":true}}"#;
+            let number = r#"{"cmd":"GUARD_HONOR_THOUSAND","data":{"status":1,"achieve":1}}"#;
+            for json in &[flag, number] {
+                match parse(json) {
+                    GuardHonorThousand { status, achieve } => assert!(status == 1 && achieve),
+                    _ => panic!("expected a guard honor"),
+                }
+            }
+            let json = r#"{"cmd":"GUARD_HONOR_THOUSAND","data":{"status":1}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_activity_banner_update() {
+            let json = r#"{"cmd":"ACTIVITY_BANNER_UPDATE_V2","data":{"id":378,"title":"第6名","cover":"https://i0.hdslb.com/bfs/live/cover.png","timestamp":1697012345,"end_time":1697616000}}"#;
+            match parse(json) {
+                ActivityBanner {
+                    title,
+                    cover,
+                    timestamp,
+                    end_time,
+                } => {
+                    assert_eq!(title, "第6名");
+                    assert_eq!(cover, "https://i0.hdslb.com/bfs/live/cover.png");
+                    assert_eq!(timestamp, 1697012345);
+                    assert_eq!(end_time, 1697616000);
+                }
+                _ => panic!("expected an activity banner"),
+            }
+        }
+
+        #[test]
+        fn ignores_the_color_of_stickers() {
+            let colored = |sticker: bool| {
+                danmaku(move |json, _| {
+                    json["info"][0][3] = 0xe33fff.into();
+                    json["info"][0][12] = if sticker { 1 } else { 0 }.into();
+                })
+            };
+            match colored(false) {
+                Danmaku { color, .. } => assert_eq!(color, 0xe33fff),
+                _ => panic!("expected a danmaku"),
+            }
+            match colored(true) {
+                Danmaku { color, .. } => assert_eq!(color, DEFAULT_DANMAKU_COLOR),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_popularity_red_pocket_new() {
+            let json = r#"{"cmd":"POPULARITY_RED_POCKET_NEW","data":{"lot_id":12345678,"uid":4204531,"uname":"路过的观众","gift_name":"红包","num":1,"remain_time":180,"price":20,"action":"送出"}}"#;
+            match parse(json) {
+                RedPocketNew {
+                    lot_id,
+                    sender_uid,
+                    sender_name,
+                    gift_name,
+                    num,
+                    remain_time,
+                } => {
+                    assert_eq!(lot_id, 12345678);
+                    assert_eq!(sender_uid, 4204531);
+                    assert_eq!(sender_name, "路过的观众");
+                    assert_eq!(gift_name, "红包");
+                    assert_eq!(num, 1);
+                    assert_eq!(remain_time, 180);
+                }
+                _ => panic!("expected a red pocket"),
+            }
+        }
+
+        #[test]
+        fn parses_danmaku_guard_icon() {
+            let icon = "https://i0.hdslb.com/bfs/live/143f5ec3003b4080d1b5f817a9efdca46d631945.png";
+            let captain = danmaku(|json, _| {
+                let medal = &mut json["info"][0][15]["user"]["medal"];
+                medal["guard_level"] = 3.into();
+                medal["guard_icon"] = icon.into();
+            });
+            match captain {
+                Danmaku { guard_icon, .. } => assert_eq!(guard_icon.as_deref(), Some(icon)),
+                _ => panic!("expected a danmaku"),
+            }
+            // Empty without a guard
+            match parse(DANMU_MSG) {
+                Danmaku { guard_icon, .. } => assert_eq!(guard_icon, None),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_lottery_danmaku() {
+            match danmaku(|json, _| json["info"][0][9] = 2.into()) {
+                Danmaku { is_lottery, .. } => assert!(is_lottery),
+                _ => panic!("expected a danmaku"),
+            }
+            match parse(DANMU_MSG) {
+                Danmaku { is_lottery, .. } => assert!(!is_lottery),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_dm_interaction_combo() {
+            // `data.data` is a json object encoded as a string
+            let json = r#"{"cmd":"DM_INTERACTION","data":{"data":"{\"combo\":[{\"id\":1234567,\"status\":4,\"content\":\"晚上好\",\"cnt\":5,\"guide\":\"他们都在说:\",\"left_duration\":17000,\"fade_duration\":10000}],\"merge_interval\":1000,\"card_appear_interval\":1000,\"send_interval\":1000}","dmscore":36,"id":9876543210,"status":4,"type":102}}"#;
+            match parse(json) {
+                DmInteraction {
+                    interaction_type,
+                    combo_count,
+                    text,
+                } => {
+                    assert_eq!(interaction_type, 102);
+                    assert_eq!(combo_count, 5);
+                    assert_eq!(text, "晚上好");
+                }
+                _ => panic!("expected a danmaku combo"),
+            }
+        }
+
+        #[test]
+        fn parses_wealth_entry_effect() {
+            let json = r##"{"cmd":"ENTRY_EFFECT","data":{"id":136,"uid":4204531,"target_id":7706705,"mock_effect":0,"face":"https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg","privilege_type":0,"copy_writing":"<%路过的观众%> 进入直播间","copy_color":"#FFFFFF","highlight_color":"#FFF100","priority":1,"basemap_url":"","show_avatar":1,"effective_time":2,"web_basemap_url":"","web_effective_time":2,"web_effect_close":1,"web_close_time":0,"business":6,"copy_writing_v2":"<%路过的观众%> 进入直播间","icon_list":[],"max_delay_time":7,"trigger_time":1697012345123456789,"identities":22,"effect_silent_time":0,"effective_time_new":0,"web_dynamic_url_webp":"","web_dynamic_url_apng":"","mobile_dynamic_url_webp":"","wealthy_info":{"uid":0,"level":30,"level_total_score":0,"cur_score":0,"upgrade_need_score":0,"status":0,"dm_icon_key":""},"new_style":1,"is_mystery":false,"uinfo":{}}}"##;
+            match parse(json) {
+                EntryEffect {
+                    uid,
+                    copy_writing,
+                    wealth_level,
+                    ..
+                } => {
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(copy_writing, "<%路过的观众%> 进入直播间");
+                    assert_eq!(wealth_level, Some(30));
+                }
+                _ => panic!("expected an entry effect"),
+            }
+
+            // A guard entrance reports wealth level 0
+            let mut json = json::parse(json).unwrap();
+            json["data"]["wealthy_info"]["level"] = 0.into();
+            match Message::parse(json).unwrap() {
+                EntryEffect { wealth_level, .. } => assert_eq!(wealth_level, None),
+                _ => panic!("expected an entry effect"),
+            }
+        }
+
+        #[test]
+        fn parses_popular_rank_guide_card() {
+            let json = r#"{"cmd":"POPULAR_RANK_GUIDE_CARD","data":{"content":"距离人气榜第 3 名还差 1200 人气值","countdown":60}}"#;
+            match parse(json) {
+                PopularRankGuideCard { content } => {
+                    assert_eq!(content, "距离人气榜第 3 名还差 1200 人气值");
+                }
+                _ => panic!("expected a rank guide card"),
+            }
+            let json = r#"{"cmd":"POPULAR_RANK_GUIDE_CARD","data":{"countdown":60}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_room_module_update() {
+            let json = r#"{"cmd":"ROOM_MODULE_UPDATE","data":{"module_list":[],"timestamp":1697012345}}"#;
+            match parse(json) {
+                RoomModuleUpdate { timestamp } => assert_eq!(timestamp, 1697012345),
+                _ => panic!("expected a module update"),
+            }
+            let json = r#"{"cmd":"ROOM_MODULE_UPDATE","data":{"module_list":[]}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_gift_star_rewards() {
+            let json = r#"{"cmd":"GIFT_STAR_PROCESS","data":{"status":2,"tip":"礼物星球已点亮","reward_name":"礼物星球徽章","reward_num":1}}"#;
+            match parse(json) {
+                GiftStarReward {
+                    reward_name,
+                    reward_num,
+                } => {
+                    assert_eq!(reward_name, "礼物星球徽章");
+                    assert_eq!(reward_num, 1);
+                }
+                _ => panic!("expected a gift star reward"),
+            }
+
+            let progress = r#"{"cmd":"GIFT_STAR_PROCESS","data":{"status":1,"tip":"已点亮 2/3","process_list":[{"gift_id":31036,"completed_num":10,"target_num":10},{"gift_id":31039,"completed_num":3,"target_num":5}]}}"#;
+            assert!(parse(progress).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_checkin_danmaku() {
+            match danmaku(|_, extra| extra["is_checkin"] = true.into()) {
+                Danmaku { is_checkin, .. } => assert!(is_checkin),
+                _ => panic!("expected a danmaku"),
+            }
+            match parse(DANMU_MSG) {
+                Danmaku { is_checkin, .. } => assert!(!is_checkin),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_online_rank_count() {
+            let json = r#"{"cmd":"ONLINE_RANK_COUNT","data":{"count":512,"count_text":"512","online_count":2048,"online_count_text":"2048"}}"#;
+            match parse(json) {
+                OnlineRankCount {
+                    count,
+                    online_count,
+                } => assert!(count == 512 && online_count == Some(2048)),
+                _ => panic!("expected a rank count"),
+            }
+
+            let json = r#"{"cmd":"ONLINE_RANK_COUNT","data":{"count":512}}"#;
+            match parse(json) {
+                OnlineRankCount {
+                    count,
+                    online_count,
+                } => assert!(count == 512 && online_count.is_none()),
+                _ => panic!("expected a rank count"),
+            }
+        }
+
+        #[test]
+        fn parses_wealth_notify() {
+            let json = r#"{"cmd":"WEALTH_NOTIFY","data":{"uid":4204531,"level":15,"score":61000,"flag":1}}"#;
+            match parse(json) {
+                WealthNotify { uid, level, score } => {
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(level, 15);
+                    assert_eq!(score, 61000);
+                }
+                _ => panic!("expected a wealth notification"),
+            }
+            let json = r#"{"cmd":"WEALTH_NOTIFY","data":{"flag":1,"info":{"effect_key":4}}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_official_accounts() {
+            let verified = |t: i32| {
+                danmaku(move |json, _| {
+                    json["info"][0][15]["user"]["base"]["official_info"]["type"] = t.into();
+                })
+            };
+            // Personal and organization verification
+            for &t in &[0, 1] {
+                match verified(t) {
+                    Danmaku { is_official, .. } => assert!(is_official),
+                    _ => panic!("expected a danmaku"),
+                }
+            }
+            match parse(DANMU_MSG) {
+                Danmaku { is_official, .. } => assert!(!is_official),
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_play_together() {
+            let json = r#"{"cmd":"PLAY_TOGETHER","data":{"status":1,"uid":4204531,"uname":"路过的观众","message":"邀请你一起玩"}}"#;
+            match parse(json) {
+                PlayTogether { status, uid, uname } => {
+                    assert_eq!(status, 1);
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                }
+                _ => panic!("expected a play together invitation"),
+            }
+            let json = r#"{"cmd":"PLAY_TOGETHER","data":{"status":1}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_change_room_info() {
+            let json = r#"{"cmd":"CHANGE_ROOM_INFO","background":"","data":{"background":"https://i0.hdslb.com/bfs/live/room_bg/background.jpg","room_id":21452505}}"#;
+            match parse(json) {
+                ChangeRoomInfo {
+                    background,
+                    room_id,
+                } => {
+                    assert_eq!(background, "https://i0.hdslb.com/bfs/live/room_bg/background.jpg");
+                    assert_eq!(room_id, 21452505);
+                }
+                _ => panic!("expected a room info change"),
+            }
+        }
+
+        #[test]
+        fn parses_both_danmaku_user_layouts() {
+            match parse(DANMU_MSG) {
+                Danmaku { uid, uname, .. } => {
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                }
+                _ => panic!("expected a danmaku"),
+            }
+            match parse(DANMU_MSG_LEGACY) {
+                Danmaku {
+                    uid, uname, text, ..
+                } => {
+                    assert_eq!(uid, 1864273);
+                    assert_eq!(uname, "老观众");
+                    assert_eq!(text, "草");
+                }
+                _ => panic!("expected a danmaku"),
+            }
+        }
+
+        #[test]
+        fn parses_guard_purchases() {
+            let json = r##"{"cmd":"USER_TOAST_MSG","data":{"anchor_show":true,"color":"#00D1F1","dmscore":90,"effect_id":397,"end_time":1697012345,"face_effect_id":44,"gift_id":10003,"guard_level":3,"is_show":0,"num":3,"op_type":1,"payflow_id":"2310111234567890123","price":474000,"role_name":"舰长","room_effect_id":590,"start_time":1697012345,"svga_block":0,"target_guard_count":1024,"toast_msg":"<%路过的观众%> 开通了3个月舰长","uid":4204531,"unit":"月","user_show":true,"username":"路过的观众"}}"##;
+            match parse(json) {
+                GuardBuy {
+                    guard_level,
+                    uid,
+                    uname,
+                    num,
+                    unit,
+                    price,
+                } => {
+                    assert!(matches!(guard_level, GuardLevel::Captain));
+                    assert_eq!(uid, 4204531);
+                    assert_eq!(uname, "路过的观众");
+                    assert_eq!(num, 3);
+                    assert_eq!(unit, "月");
+                    assert_eq!(price, 474000);
+                }
+                _ => panic!("expected a guard purchase"),
+            }
+        }
+
+        #[test]
+        fn parses_video_connections() {
+            let json = r#"{"cmd":"VIDEO_CONNECTION_JOIN_START","data":{"status":1,"invited_uid":7734200,"channel_id":"72157","invited_uname":"嘉宾","invited_face":"","start_at":1697012345},"dmscore":96}"#;
+            match parse(json) {
+                VideoConnection { status, dmscore } => {
+                    assert!(matches!(status, ConnectionStatus::Start));
+                    assert_eq!(dmscore, 96);
+                }
+                _ => panic!("expected a video connection"),
+            }
+
+            let json = r#"{"cmd":"VIDEO_CONNECTION_JOIN_END","data":{"channel_id":"72157","start_at":1697012345,"toast":"连线已结束"},"dmscore":96}"#;
+            match parse(json) {
+                VideoConnection { status, dmscore } => {
+                    assert!(matches!(status, ConnectionStatus::End));
+                    assert_eq!(dmscore, 96);
+                }
+                _ => panic!("expected a video connection"),
+            }
+        }
+
+        #[test]
+        fn parses_open_platform_super_chat() {
+            let json = r#"{"cmd":"LIVE_OPEN_PLATFORM_SUPER_CHAT","data":{"room_id":21452505,"uid":4204531,"open_id":"39b8fedb-60a5-4e29-ac75-b16955f7e632","uname":"路过的观众","uface":"https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg","message_id":7193,"message":"主播加油","msg_id":"8c2f6a1e-0d3b-4f5c-9e7a-6b1d2c3e4f50","rmb":30,"timestamp":1697012345,"start_time":1697012345,"end_time":1697012405,"guard_level":0,"fans_medal_level":12,"fans_medal_name":"小电视","fans_medal_wearing_status":true}}"#;
+            let msg = parse(json);
+            match &msg {
+                SuperChatMessage {
+                    sender_uid,
+                    face,
+                    rate,
+                    ..
+                } => {
+                    assert_eq!(*sender_uid, 4204531);
+                    assert_eq!(face, "https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg");
+                    assert_eq!(*rate, 1000);
+                }
+                _ => panic!("expected a super chat"),
+            }
+            let view = msg.super_chat().unwrap();
+            assert_eq!(view.id, "7193");
+            assert_eq!(view.price, 30);
+            assert_eq!(view.message, "主播加油");
+            assert_eq!(view.sender_name, "路过的观众");
+            assert_eq!(msg.revenue_value(), Some(30_000));
+        }
+
+        #[test]
+        fn parses_super_chat_face_and_level() {
+            match parse(SUPER_CHAT_MESSAGE) {
+                SuperChatMessage {
+                    face, user_level, ..
+                } => {
+                    assert_eq!(face, "https://i0.hdslb.com/bfs/face/b6a2f1c3d4e5.jpg");
+                    assert_eq!(user_level, 23);
+                }
+                _ => panic!("expected a super chat"),
+            }
+
+            let mut json = json::parse(SUPER_CHAT_MESSAGE).unwrap();
+            json["data"]["user_info"].remove("face");
+            json["data"]["user_info"].remove("user_level");
+            match Message::parse(json).unwrap() {
+                SuperChatMessage {
+                    face, user_level, ..
+                } => assert!(face.is_empty() && user_level == 0),
+                _ => panic!("expected a super chat"),
+            }
+        }
+
+        #[test]
+        fn parses_watched_change() {
+            let json = r#"{"cmd":"WATCHED_CHANGE","data":{"num":12345,"text_small":"1.2万","text_large":"1.2万人看过"}}"#;
+            match parse(json) {
+                WatchedChange {
+                    num,
+                    text_large,
+                    text_small,
+                } => {
+                    assert_eq!(num, 12345);
+                    assert_eq!(text_large, "1.2万人看过");
+                    assert_eq!(text_small, "1.2万");
+                }
+                _ => panic!("expected a watched change"),
+            }
+
+            let json = r#"{"cmd":"WATCHED_CHANGE","data":{"num":12345,"text_large":"1.2万人看过"}}"#;
+            match parse(json) {
+                WatchedChange { text_small, .. } => assert!(text_small.is_empty()),
+                _ => panic!("expected a watched change"),
+            }
+        }
+
+        #[test]
+        fn parses_room_silent_on() {
+            let json = r#"{"cmd":"ROOM_SILENT_ON","data":{"type":"medal","level":20,"second":-1}}"#;
+            match parse(json) {
+                ChatRestriction {
+                    guard_only,
+                    medal_level_required,
+                } => assert!(!guard_only && medal_level_required == 20),
+                _ => panic!("expected a chat restriction"),
+            }
+
+            let json = r#"{"cmd":"ROOM_SILENT_ON","data":{"type":"guard","level":1,"second":-1}}"#;
+            match parse(json) {
+                ChatRestriction {
+                    guard_only,
+                    medal_level_required,
+                } => assert!(guard_only && medal_level_required == 0),
+                _ => panic!("expected a chat restriction"),
+            }
+
+            // Restricting by user level isn't represented
+            let json = r#"{"cmd":"ROOM_SILENT_ON","data":{"type":"level","level":10,"second":-1}}"#;
+            assert!(parse(json).as_raw().is_some());
+        }
+
+        #[test]
+        fn parses_red_pocket_requirements() {
+            let red_pocket = |require_type: u32| {
+                let json = r#"{"cmd":"POPULARITY_RED_POCKET_START","data":{"lot_id":12345678,"sender_uid":4204531,"sender_name":"路过的观众","sender_face":"","join_requirement":1,"danmu":"老板大气！点点红包抽礼物","current_time":1697012345,"start_time":1697012345,"end_time":1697012525,"last_time":180,"remove_time":1697012540,"replace_time":1697012535,"lot_status":1,"h5_url":"","user_status":2,"lot_config_id":3,"total_price":1600}}"#;
+                let mut json = json::parse(json).unwrap();
+                json["data"]["require_type"] = require_type.into();
+                Message::parse(json).unwrap()
+            };
+            let expected: [fn(&RequireType) -> bool; 3] = [
+                |t| matches!(t, RequireType::None),
+                |t| matches!(t, RequireType::FollowRoom),
+                |t| matches!(t, RequireType::SendDanmaku),
+            ];
+            for (n, expected) in expected.iter().enumerate() {
+                match red_pocket(n as u32) {
+                    RedPocketStart {
+                        lot_id,
+                        sender_uid,
+                        sender_name,
+                        require_type,
+                    } => {
+                        assert_eq!(lot_id, 12345678);
+                        assert_eq!(sender_uid, 4204531);
+                        assert_eq!(sender_name, "路过的观众");
+                        assert!(expected(&require_type));
+                    }
+                    _ => panic!("expected a red pocket"),
+                }
+            }
+            assert!(red_pocket(5).as_raw().is_some());
+        }
+    }
 }